@@ -0,0 +1,477 @@
+use anyhow::{Context, Result, bail};
+use md5::{Digest, Md5};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::QualityArg;
+use crate::mp4box::{child, child_header, child_path, read_box_header, read_payload};
+use crate::tagging;
+use crate::template::date_from_unix;
+use crate::verify;
+
+/// A completed track in the download archive
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub id: u64,
+    /// Quality the file was received in (`Low`, `High`, `Lossless`, `HiRes`), or `unknown`
+    /// for imported files where it can't be told
+    pub quality: String,
+    /// Quality that was asked for, `None` for imported files
+    pub requested: Option<String>,
+    pub codec: String,
+    pub path: PathBuf,
+    pub size: u64,
+    /// MD5 of the whole file
+    pub md5: String,
+    /// Unix time the track was downloaded or imported
+    pub downloaded_at: u64,
+}
+
+impl Entry {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "quality": self.quality,
+            "requested": self.requested,
+            "codec": self.codec,
+            "path": self.path,
+            "size": self.size,
+            "md5": self.md5,
+            "downloaded_at": self.downloaded_at,
+        })
+    }
+
+    fn from_json(value: &serde_json::Value) -> Option<Self> {
+        let text = |key: &str| value[key].as_str().map(str::to_string);
+        Some(Entry {
+            id: value["id"].as_u64()?,
+            quality: text("quality")?,
+            requested: text("requested"),
+            codec: text("codec")?,
+            path: PathBuf::from(text("path")?),
+            size: value["size"].as_u64()?,
+            md5: text("md5")?,
+            downloaded_at: value["downloaded_at"].as_u64()?,
+        })
+    }
+
+    /// Describes a downloaded file, hashing it
+    pub fn for_file(
+        id: u64,
+        path: &Path,
+        quality: &str,
+        requested: Option<&str>,
+        codec: &str,
+    ) -> Result<Self> {
+        let (size, md5) = hash_file(path)?;
+        Ok(Entry {
+            id,
+            quality: quality.to_string(),
+            requested: requested.map(str::to_string),
+            codec: codec.to_string(),
+            path: std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+            size,
+            md5,
+            downloaded_at: unix_now(),
+        })
+    }
+
+    /// Whether the track doesn't have to be downloaded again for `quality`
+    ///
+    /// That is when it was received or asked for in at least that quality, so tracks that
+    /// TIDAL only offers in a lower quality aren't downloaded on every run. Such a track is
+    /// not upgraded either if TIDAL offers more later; delete its lines from the archive to
+    /// get it again. Files of unknown quality never count.
+    pub fn satisfies(&self, quality: QualityArg) -> bool {
+        let at_least = |name: &str| parse_quality(name).is_some_and(|q| q as u8 >= quality as u8);
+        at_least(&self.quality) || self.requested.as_deref().is_some_and(at_least)
+    }
+}
+
+/// Record of every track yadal downloaded, so tracks are skipped by their TIDAL ID even
+/// after their files were renamed or moved
+///
+/// The archive is a JSON Lines file. Downloads append to it, later lines replace earlier
+/// ones for the same track.
+pub struct Archive {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<u64, Entry>>,
+}
+
+impl Archive {
+    /// Opens the archive at `path`, which doesn't have to exist yet
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut entries = BTreeMap::new();
+        let mut truncated = false;
+        match std::fs::read_to_string(path) {
+            Ok(data) => {
+                let lines: Vec<&str> = data.lines().collect();
+                for (i, line) in lines.iter().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let entry = serde_json::from_str(line)
+                        .ok()
+                        .and_then(|value| Entry::from_json(&value));
+                    match entry {
+                        Some(entry) => {
+                            entries.insert(entry.id, entry);
+                        }
+                        // a run that was killed while recording a track leaves half a line
+                        None if i == lines.len() - 1 && !data.ends_with('\n') => {
+                            println!(
+                                "Ignoring incomplete last entry of download archive {}",
+                                path.display()
+                            );
+                            truncated = true;
+                        }
+                        None => bail!("Invalid entry on line {} of {}", i + 1, path.display()),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Failed to read download archive {}", path.display())
+                });
+            }
+        }
+
+        // new entries are appended, which would continue the broken line
+        if truncated {
+            write_all(path, entries.values())?;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn get(&self, id: u64) -> Option<Entry> {
+        self.entries.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Adds or replaces the entry of a track
+    pub fn record(&self, entry: Entry) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).context("Failed to create archive directory")?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context("Failed to open download archive")?;
+        writeln!(file, "{}", entry.to_json()).context("Failed to write download archive")?;
+
+        entries.insert(entry.id, entry);
+        Ok(())
+    }
+
+    /// Keeps only the entries `keep` returns true for, returning the removed ones
+    fn retain(&self, mut keep: impl FnMut(&Entry) -> bool) -> Result<Vec<Entry>> {
+        let mut entries = self.entries.lock().unwrap();
+        let removed: Vec<Entry> = entries.values().filter(|e| !keep(e)).cloned().collect();
+        for entry in &removed {
+            entries.remove(&entry.id);
+        }
+        write_all(&self.path, entries.values())?;
+        Ok(removed)
+    }
+}
+
+/// Rewrites the archive with one line per entry
+fn write_all<'a>(path: &Path, entries: impl Iterator<Item = &'a Entry>) -> Result<()> {
+    let mut data = String::new();
+    for entry in entries {
+        data.push_str(&entry.to_json().to_string());
+        data.push('\n');
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).context("Failed to create archive directory")?;
+    }
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    std::fs::write(&tmp_path, data).context("Failed to write download archive")?;
+    std::fs::rename(&tmp_path, path).context("Failed to replace download archive")
+}
+
+/// Prints every track in the archive
+pub fn list(archive: &Archive) {
+    let entries = archive.entries();
+    for entry in &entries {
+        println!(
+            "{}  {}  {:<8}  {:<9}  {:>10}  {}",
+            entry.id,
+            date_from_unix(entry.downloaded_at),
+            entry.quality,
+            entry.codec,
+            indicatif::HumanBytes(entry.size).to_string(),
+            entry.path.display()
+        );
+    }
+    println!("\n{} tracks in {}", entries.len(), archive.path.display());
+}
+
+/// Adds the FLAC and MP4 files below `dir` that carry a TIDAL track ID tag
+pub fn import(archive: &Archive, dir: &Path) -> Result<()> {
+    let mut files = Vec::new();
+    verify::collect_audio_files(dir, &mut files)?;
+    files.sort();
+
+    let mut imported = 0;
+    let mut untagged = 0;
+    let mut failed = 0;
+    for path in &files {
+        let name = path.strip_prefix(dir).unwrap_or(path).display();
+        let result = tagging::read_track_id(path).and_then(|id| match id {
+            Some(id) => {
+                let (codec, quality) = probe(path)?;
+                archive.record(Entry::for_file(id, path, &quality, None, &codec)?)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        });
+
+        match result {
+            Ok(true) => imported += 1,
+            Ok(false) => untagged += 1,
+            Err(e) => {
+                failed += 1;
+                println!("✗ {} ({:#})", name, e);
+            }
+        }
+    }
+
+    println!("\nsummary:");
+    println!("  imported: {}", imported);
+    if untagged > 0 {
+        println!("  without TIDAL track ID: {}", untagged);
+    }
+    if failed > 0 {
+        println!("  failed: {}", failed);
+        bail!("{} files could not be imported", failed);
+    }
+    Ok(())
+}
+
+/// Removes the entries whose file no longer exists at the recorded path
+///
+/// The removed tracks are downloaded again by the next run that includes them.
+pub fn prune(archive: &Archive) -> Result<()> {
+    let removed = archive.retain(|entry| entry.path.exists())?;
+    for entry in &removed {
+        println!("- {} ({})", entry.path.display(), entry.id);
+    }
+    println!("\nremoved {} entries of missing files", removed.len());
+    Ok(())
+}
+
+/// Size and MD5 (hex) of a file
+fn hash_file(path: &Path) -> Result<(u64, String)> {
+    let mut file = File::open(path).context("Failed to open file")?;
+    let mut md5 = Md5::new();
+    let size = std::io::copy(&mut file, &mut md5).context("Failed to read file")?;
+    let hex = md5
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Ok((size, hex))
+}
+
+/// Codec and quality of an audio file, as far as the file tells
+fn probe(path: &Path) -> Result<(String, String)> {
+    let mut reader = BufReader::new(File::open(path).context("Failed to open file")?);
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .context("Failed to read file header")?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    if magic.starts_with(b"fLaC") {
+        // the marker and STREAMINFO block header come before its data
+        let mut head = [0u8; 42];
+        reader
+            .read_exact(&mut head)
+            .context("Truncated FLAC stream")?;
+        return Ok(("flac".to_string(), flac_quality(&head[8..]).to_string()));
+    }
+
+    while let Some(header) = read_box_header(&mut reader)? {
+        if &header.box_type == b"moov" {
+            let moov = read_payload(&mut reader, &header)?;
+            let stsd = child_path(&moov, &[b"trak", b"mdia", b"minf", b"stbl", b"stsd"])?
+                .context("No sample description in MP4")?;
+            let entry = stsd.get(8..).context("Truncated 'stsd' box")?;
+            let (entry_type, header_len, entry_size) = child_header(entry)?;
+
+            return Ok(match &entry_type {
+                b"fLaC" => {
+                    // STREAMINFO in the dfLa box behind the audio sample entry fields
+                    let quality = entry
+                        .get(header_len + 28..entry_size)
+                        .and_then(|children| child(children, b"dfLa").ok().flatten())
+                        .and_then(|dfla| dfla.get(8..))
+                        .map_or("unknown", flac_quality);
+                    ("flac".to_string(), quality.to_string())
+                }
+                b"mp4a" => ("aac".to_string(), "unknown".to_string()),
+                other => (
+                    String::from_utf8_lossy(other).trim().to_lowercase(),
+                    "unknown".to_string(),
+                ),
+            });
+        }
+
+        let Some(size) = header.size else {
+            break;
+        };
+        reader.seek(SeekFrom::Current((size - header.header_len) as i64))?;
+    }
+    bail!("No moov box found")
+}
+
+/// TIDAL quality of a FLAC stream from its STREAMINFO: more than 16 bits or 48 kHz is
+/// HiRes
+fn flac_quality(streaminfo: &[u8]) -> &'static str {
+    let Some(info) = streaminfo.get(..18) else {
+        return "unknown";
+    };
+    let sample_rate = (info[10] as u32) << 12 | (info[11] as u32) << 4 | (info[12] as u32) >> 4;
+    let bits_per_sample = ((info[12] & 0x01) << 4 | info[13] >> 4) + 1;
+    if bits_per_sample > 16 || sample_rate > 48_000 {
+        "HiRes"
+    } else {
+        "Lossless"
+    }
+}
+
+/// Reads a quality name as the archive stores it
+pub fn parse_quality(name: &str) -> Option<QualityArg> {
+    match name {
+        "Low" => Some(QualityArg::Low),
+        "High" => Some(QualityArg::High),
+        "Lossless" => Some(QualityArg::Lossless),
+        "HiRes" => Some(QualityArg::HiRes),
+        _ => None,
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: u64, quality: &str, requested: Option<&str>) -> Entry {
+        Entry {
+            id,
+            quality: quality.to_string(),
+            requested: requested.map(str::to_string),
+            codec: "flac".to_string(),
+            path: PathBuf::from(format!("/music/{}.flac", id)),
+            size: 1000,
+            md5: "0".repeat(32),
+            downloaded_at: 1_700_000_000,
+        }
+    }
+
+    #[test]
+    fn test_record_and_reopen() {
+        let dir = std::env::temp_dir().join(format!("yadal-archive-{}", std::process::id()));
+        let path = dir.join("archive.jsonl");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let archive = Archive::open(&path).unwrap();
+        archive.record(entry(1, "Lossless", Some("HiRes"))).unwrap();
+        archive.record(entry(2, "High", Some("High"))).unwrap();
+        // a later download of the same track replaces its entry
+        archive.record(entry(1, "HiRes", Some("HiRes"))).unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(archive.entries().len(), 2);
+        assert_eq!(archive.get(1), Some(entry(1, "HiRes", Some("HiRes"))));
+
+        // none of the files exist
+        assert_eq!(archive.retain(|e| e.id == 2).unwrap().len(), 1);
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(archive.entries(), [entry(2, "High", Some("High"))]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_archive() {
+        let dir =
+            std::env::temp_dir().join(format!("yadal-archive-damaged-{}", std::process::id()));
+        let path = dir.join("archive.jsonl");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let line = |id| {
+            entry(id, "Lossless", Some("Lossless"))
+                .to_json()
+                .to_string()
+        };
+
+        // a half-written last line is dropped, and later entries start on a line of their own
+        std::fs::write(&path, format!("{}\n{{\"id\":2,\"qual", line(1))).unwrap();
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(archive.entries().len(), 1);
+        archive.record(entry(3, "HiRes", Some("HiRes"))).unwrap();
+        assert_eq!(Archive::open(&path).unwrap().entries().len(), 2);
+
+        // damage anywhere else isn't guessed around
+        std::fs::write(&path, format!("{}\n{{\"id\":2\n{}\n", line(1), line(3))).unwrap();
+        let err = Archive::open(&path).err().unwrap();
+        assert!(err.to_string().contains("line 2"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_satisfies() {
+        // received in a lower quality because TIDAL didn't offer more
+        let capped = entry(1, "Lossless", Some("HiRes"));
+        assert!(capped.satisfies(QualityArg::HiRes));
+        assert!(capped.satisfies(QualityArg::Low));
+
+        let lossy = entry(2, "High", Some("High"));
+        assert!(!lossy.satisfies(QualityArg::Lossless));
+
+        // imported files count for the quality they were detected in
+        let imported = entry(3, "Lossless", None);
+        assert!(imported.satisfies(QualityArg::Lossless));
+        assert!(!imported.satisfies(QualityArg::HiRes));
+
+        let unknown = entry(4, "unknown", None);
+        assert!(!unknown.satisfies(QualityArg::Low));
+    }
+
+    #[test]
+    fn test_flac_quality() {
+        let mut info = [0u8; 34];
+        // 44100 Hz, 16 bits
+        info[10..14].copy_from_slice(&[0x0a, 0xc4, 0x42, 0xf0]);
+        assert_eq!(flac_quality(&info), "Lossless");
+        // 96000 Hz, 24 bits
+        info[10..14].copy_from_slice(&[0x17, 0x70, 0x03, 0x70]);
+        assert_eq!(flac_quality(&info), "HiRes");
+    }
+}
@@ -1,9 +1,13 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use directories::ProjectDirs;
 
-use crate::{MediaTypeArg, QualityArg};
+use crate::template::{DEFAULT_ALBUM_TEMPLATE, DEFAULT_MIX_TEMPLATE, DEFAULT_PLAYLIST_TEMPLATE};
+use crate::{
+    CoverSizeArg, DashContainerArg, DiscLayoutArg, FavoritesArg, MediaTypeArg, QualityArg,
+    ReleaseTypeArg, VideoQualityArg,
+};
 
 fn default_session_file() -> PathBuf {
     ProjectDirs::from("", "", "yadal")
@@ -11,19 +15,81 @@ fn default_session_file() -> PathBuf {
         .unwrap_or_else(|| PathBuf::from("session.json"))
 }
 
+fn default_archive_file() -> PathBuf {
+    ProjectDirs::from("", "", "yadal")
+        .map(|proj_dirs| proj_dirs.data_dir().join("archive.jsonl"))
+        .unwrap_or_else(|| PathBuf::from("archive.jsonl"))
+}
+
+fn parse_release_date(value: &str) -> Result<String, String> {
+    let parts: Vec<&str> = value.split('-').collect();
+    let valid = parts.len() <= 3
+        && parts
+            .iter()
+            .zip([4, 2, 2])
+            .all(|(part, width)| part.len() == width && part.bytes().all(|b| b.is_ascii_digit()));
+
+    if valid {
+        Ok(value.to_string())
+    } else {
+        Err("expected YYYY, YYYY-MM or YYYY-MM-DD".to_string())
+    }
+}
+
+/// Parses a byte count with an optional K, M, G or T suffix (powers of 1024)
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(at) => value.split_at(at),
+        None => (value, ""),
+    };
+    let factor: u64 = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err("expected a size like 800K, 5M or 20G".to_string()),
+    };
+
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| "expected a size like 800K, 5M or 20G".to_string())?;
+    if !number.is_finite() || number <= 0.0 {
+        return Err("size must be positive".to_string());
+    }
+    Ok((number * factor as f64) as u64)
+}
+
 #[derive(Parser)]
 #[command(name = "tidal-downloader")]
 #[command(author, version, about = "Download music from TIDAL", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Cli {
-    /// TIDAL URL or media ID (track, album, or playlist)
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TIDAL URLs or media IDs (track, album, playlist, artist, mix, or video), or - to read
+    /// them from stdin
     ///
     /// Examples:
     ///   https://tidal.com/track/437468401
     ///   https://tidal.com/album/55130630
     ///   https://tidal.com/playlist/aa692128-2954-4fe1-b5a1-4ede1add485d
+    ///   https://tidal.com/artist/3346
+    ///   https://tidal.com/mix/0017159e6a1f34ae3d981792d72ecf
+    ///   https://tidal.com/video/75623239
     ///   437468401
-    #[arg(value_name = "URL_OR_ID")]
-    pub id: String,
+    #[arg(value_name = "URL_OR_ID", required_unless_present = "input_file")]
+    pub ids: Vec<String>,
+
+    /// Read URLs or IDs from a file, one per line (- for stdin)
+    ///
+    /// Blank lines and lines starting with # are ignored. A line may override the media type
+    /// and quality, e.g. "https://tidal.com/album/55130630 --quality lossless"
+    #[arg(short, long, value_name = "PATH")]
+    pub input_file: Vec<PathBuf>,
 
     /// Type of media to download
     #[arg(short, long, value_enum, default_value = "auto")]
@@ -33,14 +99,128 @@ pub struct Cli {
     #[arg(short, long, value_enum, default_value = "hi-res")]
     pub quality: QualityArg,
 
+    /// Container for hi-res FLAC streams, which TIDAL delivers as fragmented MP4
+    ///
+    /// flac remuxes them into native FLAC files, m4a keeps the MP4 container
+    #[arg(long, value_enum, default_value = "flac")]
+    pub dash_container: DashContainerArg,
+
     /// Output directory
     #[arg(short, long, default_value = "yadal")]
     pub output: PathBuf,
 
+    /// Maximum video resolution (none skips videos in albums, playlists and mixes)
+    #[arg(long, value_enum, default_value = "1080")]
+    pub video_quality: VideoQualityArg,
+
+    /// Album cover resolution to embed and save (none disables covers)
+    #[arg(long, value_enum, default_value = "1280")]
+    pub cover_size: CoverSizeArg,
+
+    /// File name the album cover is saved as in each album directory
+    #[arg(long, default_value = "cover.jpg")]
+    pub cover_file: String,
+
+    /// Album directory template
+    ///
+    /// Placeholders: {album_artist} {artist} {album} {year} {version} {explicit} {tidal_id}
+    #[arg(long, default_value = DEFAULT_ALBUM_TEMPLATE)]
+    pub album_template: String,
+
+    /// Playlist directory template
+    ///
+    /// Placeholders: {playlist} {tidal_id} {date}
+    #[arg(long, default_value = DEFAULT_PLAYLIST_TEMPLATE)]
+    pub playlist_template: String,
+
+    /// Mix directory template, e.g. "{mix} {date}" for dated snapshots of daily and weekly mixes
+    ///
+    /// Placeholders: {mix} {tidal_id} {date}
+    #[arg(long, default_value = DEFAULT_MIX_TEMPLATE)]
+    pub mix_template: String,
+
+    /// Track file name template, without extension (overrides --disc-layout)
+    ///
+    /// Placeholders: {album_artist} {artist} {album} {year} {disc} {track} {position}
+    /// {title} {version} {explicit} {quality} {isrc} {tidal_id}.
+    /// Use {track:02} to zero-pad, <...> for sections dropped when a value inside is empty,
+    /// and / for subdirectories, e.g. "{track:02} - {title}< ({version})>"
+    #[arg(long)]
+    pub track_template: Option<String>,
+
+    /// How to separate discs of multi-disc albums (CD1/ subfolders or 1-01 prefixes)
+    #[arg(long, value_enum, default_value = "subfolders")]
+    pub disc_layout: DiscLayoutArg,
+
+    /// Release types to download from an artist's discography
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "album,ep,single"
+    )]
+    pub release_types: Vec<ReleaseTypeArg>,
+
+    /// Only download artist releases from this date on (YYYY, YYYY-MM or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_release_date)]
+    pub released_after: Option<String>,
+
+    /// Only download artist releases up to this date (YYYY, YYYY-MM or YYYY-MM-DD)
+    #[arg(long, value_parser = parse_release_date)]
+    pub released_before: Option<String>,
+
+    /// Don't verify track files after downloading them
+    #[arg(long)]
+    pub no_verify: bool,
+
     /// Maximum parallel downloads
     #[arg(short, long, default_value = "5")]
     pub parallel: usize,
 
+    /// Maximum DASH and video segments fetched at once, across all tracks
+    #[arg(long, default_value = "20")]
+    pub segment_parallel: usize,
+
+    /// Limit the combined download rate, in bytes per second (e.g. 500K, 5M)
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    pub limit_rate: Option<u64>,
+
+    /// Stop after downloading this many tracks and videos (existing files don't count)
+    #[arg(long, value_name = "N")]
+    pub max_downloads: Option<u64>,
+
+    /// Stop after downloading this much data (e.g. 700M, 20G)
+    ///
+    /// Running downloads stop too and are resumed by the next run
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_bytes: Option<u64>,
+
+    /// Seconds to wait for a connection to the server
+    #[arg(long, value_name = "SECS", default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    pub connect_timeout: u64,
+
+    /// Seconds without receiving any data before a download is retried
+    #[arg(long, value_name = "SECS", default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    pub stall_timeout: u64,
+
+    /// Retry downloads slower than this, in bytes per second averaged over the stall timeout
+    /// (e.g. 50K)
+    #[arg(long, value_name = "RATE", value_parser = parse_size)]
+    pub min_speed: Option<u64>,
+
+    /// Tracks whose playback info is fetched ahead of their download
+    #[arg(long, value_name = "N", default_value = "10")]
+    pub prefetch: usize,
+
+    /// Maximum TIDAL API requests per second for playback info
+    #[arg(long, value_name = "N", default_value = "5", value_parser = clap::value_parser!(u64).range(1..))]
+    pub api_rate: u64,
+
+    /// Adapt the number of parallel downloads and segments to how the server copes, up to
+    /// --parallel and --segment-parallel
+    #[arg(long)]
+    pub adaptive: bool,
+
     /// Force re-authentication
     #[arg(long)]
     pub reauth: bool,
@@ -48,4 +228,57 @@ pub struct Cli {
     /// Session file path
     #[arg(long, value_parser, default_value_os_t = default_session_file())]
     pub session_file: PathBuf,
+
+    /// Record of downloaded tracks, which are skipped even after their files were renamed
+    /// or moved
+    ///
+    /// Tracks received in a lower quality than asked for because TIDAL didn't offer more
+    /// are skipped as well, so they are not upgraded if TIDAL offers more later. Delete their
+    /// lines from the archive file to download them again.
+    #[arg(long, value_name = "FILE", default_value_os_t = default_archive_file())]
+    pub download_archive: PathBuf,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Download the logged-in user's collection
+    ///
+    /// Download options go before the subcommand, e.g. `yadal -q lossless favorites albums`
+    Favorites {
+        /// Part of the collection to download
+        #[arg(value_enum)]
+        kind: FavoritesArg,
+    },
+
+    /// Check the FLAC and MP4 audio files in a directory for damage
+    ///
+    /// Decodes FLAC files completely and compares their MD5 signature, and checks the box
+    /// structure and sample tables of MP4 files
+    Verify {
+        /// Directory to check, including its subdirectories
+        dir: PathBuf,
+    },
+
+    /// Manage the download archive
+    ///
+    /// The archive file is set with --download-archive before the subcommand
+    Archive {
+        #[command(subcommand)]
+        action: ArchiveCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ArchiveCommand {
+    /// List the tracks in the archive
+    List,
+
+    /// Add existing FLAC and MP4 files to the archive by their TIDAL track ID tag
+    Import {
+        /// Directory to import, including its subdirectories
+        dir: PathBuf,
+    },
+
+    /// Remove tracks whose files no longer exist, so they are downloaded again
+    Prune,
 }
@@ -103,7 +103,7 @@ pub async fn authenticate(session_file: &Path) -> Result<TidalClient> {
     Ok(client)
 }
 
-fn save_session(client: &TidalClient, session_file: &Path) -> Result<()> {
+pub fn save_session(client: &TidalClient, session_file: &Path) -> Result<()> {
     if let Some(parent) = session_file.parent() {
         std::fs::create_dir_all(parent).context("Failed to create session directory")?;
     }
@@ -0,0 +1,174 @@
+use anyhow::Result;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OnceCell};
+
+use crate::downloader::Timeouts;
+
+/// Resolution album covers are requested at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoverSize {
+    Pixels(u32),
+    Original,
+}
+
+/// Album cover image, shared by every track of the album
+#[derive(Debug, Clone)]
+pub struct Cover {
+    pub data: Bytes,
+    pub mime_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Cover {
+    fn from_bytes(data: Bytes) -> Self {
+        let (mime_type, (width, height)) = if data.starts_with(b"\x89PNG") {
+            ("image/png", png_dimensions(&data).unwrap_or((0, 0)))
+        } else {
+            ("image/jpeg", jpeg_dimensions(&data).unwrap_or((0, 0)))
+        };
+
+        Self {
+            data,
+            mime_type,
+            width,
+            height,
+        }
+    }
+}
+
+/// Caches covers by cover ID so each album cover is fetched once per run
+pub struct CoverCache {
+    size: Option<CoverSize>,
+    timeouts: Timeouts,
+    entries: Mutex<HashMap<String, Arc<OnceCell<Option<Cover>>>>>,
+}
+
+impl CoverCache {
+    pub fn new(size: Option<CoverSize>, timeouts: Timeouts) -> Self {
+        Self {
+            size,
+            timeouts,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.size.is_some()
+    }
+
+    /// Returns the cover, fetching it on first use. `None` if covers are disabled or unavailable
+    pub async fn get(&self, http_client: &reqwest::Client, cover_id: &str) -> Option<Cover> {
+        let size = self.size?;
+        if cover_id.is_empty() {
+            return None;
+        }
+
+        let cell = {
+            let mut entries = self.entries.lock().await;
+            Arc::clone(entries.entry(cover_id.to_string()).or_default())
+        };
+
+        cell.get_or_init(async || {
+            fetch_cover(http_client, &self.timeouts, cover_id, size)
+                .await
+                .ok()
+        })
+        .await
+        .clone()
+    }
+}
+
+async fn fetch_cover(
+    http_client: &reqwest::Client,
+    timeouts: &Timeouts,
+    cover_id: &str,
+    size: CoverSize,
+) -> Result<Cover> {
+    let response = timeouts
+        .send(http_client.get(cover_url(cover_id, size)))
+        .await?;
+
+    if !response.status().is_success() {
+        anyhow::bail!("HTTP {}", response.status());
+    }
+
+    // original covers can be large, so a slow link only fails when it stalls
+    let mut data = Vec::new();
+    let mut stream = response.bytes_stream();
+    let mut body = timeouts.body();
+    while let Some(chunk) = body.next(&mut stream).await? {
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Cover::from_bytes(Bytes::from(data)))
+}
+
+/// TIDAL image URLs use the cover UUID with dashes turned into path separators
+fn cover_url(cover_id: &str, size: CoverSize) -> String {
+    let path = cover_id.replace('-', "/");
+    match size {
+        CoverSize::Pixels(px) => format!(
+            "https://resources.tidal.com/images/{}/{}x{}.jpg",
+            path, px, px
+        ),
+        CoverSize::Original => format!("https://resources.tidal.com/images/{}/origin.jpg", path),
+    }
+}
+
+fn png_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let width = data.get(16..20)?;
+    let height = data.get(20..24)?;
+    Some((
+        u32::from_be_bytes(width.try_into().ok()?),
+        u32::from_be_bytes(height.try_into().ok()?),
+    ))
+}
+
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let mut i = 2;
+    while i + 9 < data.len() {
+        if data[i] != 0xFF {
+            return None;
+        }
+        let marker = data[i + 1];
+        let length = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+
+        // SOF0-SOF15, excluding DHT, JPG and DAC which share the range
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            let height = u16::from_be_bytes([data[i + 5], data[i + 6]]) as u32;
+            let width = u16::from_be_bytes([data[i + 7], data[i + 8]]) as u32;
+            return Some((width, height));
+        }
+
+        i += 2 + length;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cover_url() {
+        assert_eq!(
+            cover_url("a1b2-c3d4", CoverSize::Pixels(1280)),
+            "https://resources.tidal.com/images/a1b2/c3d4/1280x1280.jpg"
+        );
+        assert_eq!(
+            cover_url("a1b2-c3d4", CoverSize::Original),
+            "https://resources.tidal.com/images/a1b2/c3d4/origin.jpg"
+        );
+    }
+
+    #[test]
+    fn test_jpeg_dimensions() {
+        let mut data = vec![0xFF, 0xD8];
+        data.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00]); // APP0
+        data.extend_from_slice(&[0xFF, 0xC0, 0x00, 0x11, 0x08, 0x02, 0x80, 0x05, 0x00]);
+        data.extend_from_slice(&[0u8; 12]);
+        assert_eq!(jpeg_dimensions(&data), Some((1280, 640)));
+    }
+}
@@ -0,0 +1,366 @@
+use anyhow::{Context, Result, bail};
+use reqwest::Url;
+
+/// Most segments a manifest may list, far more than any track or video has
+///
+/// Repeat counts and segment durations come from the manifest, so without a limit a broken
+/// one could make the segment list exhaust memory.
+const MAX_SEGMENTS: u64 = 100_000;
+
+/// Segments of a DASH representation, in playback order
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentList {
+    pub init_url: String,
+    pub segments: Vec<Segment>,
+    /// Units per second of the segment durations
+    pub timescale: u64,
+    /// Codecs of the representation, such as `flac` or `mp4a.40.2`
+    pub codecs: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub url: String,
+    /// Length of the segment in timescale units
+    pub duration: u64,
+}
+
+impl SegmentList {
+    /// Combined length of all segments in seconds
+    pub fn duration_secs(&self) -> f64 {
+        let total: u64 = self.segments.iter().map(|s| s.duration).sum();
+        total as f64 / self.timescale as f64
+    }
+}
+
+/// Builds the exact segment list of the first representation in an MPD
+///
+/// Segments come from the SegmentTemplate's SegmentTimeline (durations and repeat counts), or
+/// from its fixed segment duration and the presentation duration when there is no timeline.
+pub fn parse_manifest(mpd: &str) -> Result<SegmentList> {
+    let document = roxmltree::Document::parse(mpd).context("Invalid DASH manifest")?;
+    let root = document.root_element();
+
+    let representation = root
+        .descendants()
+        .find(|n| n.has_tag_name("Representation"))
+        .context("No representation in DASH manifest")?;
+
+    // a SegmentTemplate may sit on the representation or be inherited from its parents
+    let template = representation
+        .ancestors()
+        .find_map(|n| n.children().find(|c| c.has_tag_name("SegmentTemplate")))
+        .context("No segment template in DASH manifest")?;
+
+    // BaseURLs of all levels resolve against each other, outermost first
+    let mut base: Option<Url> = None;
+    for node in representation
+        .ancestors()
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+    {
+        if let Some(text) = node
+            .children()
+            .find(|c| c.has_tag_name("BaseURL"))
+            .and_then(|c| c.text())
+        {
+            base = Some(resolve(base.as_ref(), text.trim())?);
+        }
+    }
+
+    let representation_id = representation.attribute("id").unwrap_or_default();
+    let bandwidth = representation.attribute("bandwidth").unwrap_or_default();
+    let timescale = number(template.attribute("timescale"), 1)?;
+    let start_number = number(template.attribute("startNumber"), 1)?;
+    let codecs = representation
+        .ancestors()
+        .find_map(|n| n.attribute("codecs"))
+        .map(str::to_string);
+
+    let fill = |pattern: &str, number: u64, time: u64| -> Result<String> {
+        let path = fill_template(pattern, representation_id, bandwidth, number, time)?;
+        Ok(resolve(base.as_ref(), &path)?.to_string())
+    };
+
+    let init_url = fill(
+        template
+            .attribute("initialization")
+            .context("No initialization segment in DASH manifest")?,
+        start_number,
+        0,
+    )?;
+    let media = template
+        .attribute("media")
+        .context("No media segments in DASH manifest")?;
+
+    // (start time, duration) of every segment
+    let mut timing = Vec::new();
+    if let Some(timeline) = template
+        .children()
+        .find(|c| c.has_tag_name("SegmentTimeline"))
+    {
+        let mut time = 0;
+        for s in timeline.children().filter(|c| c.has_tag_name("S")) {
+            if let Some(t) = s.attribute("t") {
+                time = number(Some(t), 0)?;
+            }
+            let duration = number(s.attribute("d"), 0)?;
+            if duration == 0 {
+                bail!("Segment without duration in DASH manifest");
+            }
+            let repeat: i64 = s
+                .attribute("r")
+                .map(str::parse)
+                .transpose()
+                .context("Invalid segment repeat count in DASH manifest")?
+                .unwrap_or(0);
+            if repeat < 0 {
+                bail!("Open-ended segment repeats are not supported");
+            }
+            if timing.len() as u64 + repeat as u64 >= MAX_SEGMENTS {
+                bail!("DASH manifest lists more than {} segments", MAX_SEGMENTS);
+            }
+
+            for _ in 0..=repeat {
+                timing.push((time, duration));
+                time += duration;
+            }
+        }
+    } else {
+        let duration = number(template.attribute("duration"), 0)?;
+        let total = root
+            .attribute("mediaPresentationDuration")
+            .and_then(parse_duration)
+            .context("No segment timeline or presentation duration in DASH manifest")?;
+        if duration == 0 {
+            bail!("No segment duration in DASH manifest");
+        }
+
+        let total_units = (total * timescale as f64).round() as u64;
+        let count = total_units.div_ceil(duration);
+        if count > MAX_SEGMENTS {
+            bail!("DASH manifest lists more than {} segments", MAX_SEGMENTS);
+        }
+        for index in 0..count {
+            let start = index * duration;
+            timing.push((start, duration.min(total_units - start)));
+        }
+    }
+
+    if timing.is_empty() {
+        bail!("No segments in DASH manifest");
+    }
+
+    let segments = timing
+        .into_iter()
+        .zip(start_number..)
+        .map(|((time, duration), number)| {
+            Ok(Segment {
+                url: fill(media, number, time)?,
+                duration,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(SegmentList {
+        init_url,
+        segments,
+        timescale,
+        codecs,
+    })
+}
+
+fn number(value: Option<&str>, default: u64) -> Result<u64> {
+    match value {
+        Some(value) => value
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid number {:?} in DASH manifest", value)),
+        None => Ok(default),
+    }
+}
+
+fn resolve(base: Option<&Url>, url: &str) -> Result<Url> {
+    match base {
+        Some(base) => base.join(url),
+        None => Url::parse(url),
+    }
+    .with_context(|| format!("Invalid URL {:?} in DASH manifest", url))
+}
+
+/// Substitutes `$RepresentationID$`, `$Bandwidth$`, `$Number$` and `$Time$` identifiers,
+/// including printf-style widths such as `$Number%05d$`
+fn fill_template(
+    pattern: &str,
+    representation_id: &str,
+    bandwidth: &str,
+    number: u64,
+    time: u64,
+) -> Result<String> {
+    let mut out = String::new();
+    let mut parts = pattern.split('$');
+
+    // identifiers sit between pairs of `$`
+    out.push_str(parts.next().unwrap_or_default());
+    while let Some(identifier) = parts.next() {
+        let (name, format) = identifier.split_once('%').unwrap_or((identifier, ""));
+        let width: usize = format
+            .strip_prefix('0')
+            .unwrap_or(format)
+            .trim_end_matches('d')
+            .parse()
+            .unwrap_or(0);
+
+        match name {
+            "" => out.push('$'),
+            "RepresentationID" => out.push_str(representation_id),
+            "Bandwidth" => out.push_str(bandwidth),
+            "Number" => out.push_str(&format!("{:0width$}", number)),
+            "Time" => out.push_str(&format!("{:0width$}", time)),
+            _ => bail!("Unknown identifier ${}$ in DASH segment template", name),
+        }
+
+        match parts.next() {
+            Some(text) => out.push_str(text),
+            None => bail!("Unterminated identifier in DASH segment template"),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Parses an ISO 8601 duration like `PT3M25.123S` into seconds
+fn parse_duration(value: &str) -> Option<f64> {
+    let time = value.strip_prefix("PT")?;
+    let mut seconds = 0.0;
+    let mut digits = String::new();
+
+    for c in time.chars() {
+        match c {
+            'H' => seconds += digits.parse::<f64>().ok()? * 3600.0,
+            'M' => seconds += digits.parse::<f64>().ok()? * 60.0,
+            'S' => seconds += digits.parse::<f64>().ok()?,
+            _ => {
+                digits.push(c);
+                continue;
+            }
+        }
+        digits.clear();
+    }
+
+    digits.is_empty().then_some(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MPD: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" mediaPresentationDuration="PT0M12.5S" type="static">
+  <Period id="0">
+    <AdaptationSet contentType="audio" mimeType="audio/mp4">
+      <Representation id="FLAC,44100,24" codecs="flac" bandwidth="1500000">
+        <SegmentTemplate timescale="44100" initialization="https://sp.example.com/track/0.mp4?token=abc"
+            media="https://sp.example.com/track/$Number$.mp4?token=abc" startNumber="1">
+          <SegmentTimeline>
+            <S d="176128" r="2"/>
+            <S d="22050"/>
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    #[test]
+    fn test_parse_segment_timeline() {
+        let list = parse_manifest(MPD).unwrap();
+        assert_eq!(
+            list.init_url,
+            "https://sp.example.com/track/0.mp4?token=abc"
+        );
+        assert_eq!(list.segments.len(), 4);
+        assert_eq!(
+            list.segments[0].url,
+            "https://sp.example.com/track/1.mp4?token=abc"
+        );
+        assert_eq!(
+            list.segments[3].url,
+            "https://sp.example.com/track/4.mp4?token=abc"
+        );
+        assert_eq!(list.segments[3].duration, 22050);
+        assert_eq!(list.codecs.as_deref(), Some("flac"));
+        assert!((list.duration_secs() - (3.0 * 176128.0 + 22050.0) / 44100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_too_many_segments() {
+        let huge_repeat = MPD.replace(r#"r="2""#, r#"r="9000000000000""#);
+        let err = parse_manifest(&huge_repeat).unwrap_err();
+        assert!(err.to_string().contains("more than"));
+
+        let tiny_segments = r#"<MPD mediaPresentationDuration="PT100000H">
+  <Period>
+    <AdaptationSet>
+      <Representation id="1">
+        <SegmentTemplate timescale="1" duration="1" initialization="https://cdn.example.com/init.mp4"
+            media="https://cdn.example.com/$Number$.mp4"/>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        let err = parse_manifest(tiny_segments).unwrap_err();
+        assert!(err.to_string().contains("more than"));
+    }
+
+    #[test]
+    fn test_parse_fixed_duration_with_base_url() {
+        let mpd = r#"<MPD mediaPresentationDuration="PT1M0.5S">
+  <BaseURL>https://cdn.example.com/audio/</BaseURL>
+  <Period>
+    <AdaptationSet>
+      <SegmentTemplate timescale="1000" duration="4000" startNumber="0"
+          initialization="$RepresentationID$/init.mp4" media="$RepresentationID$/seg-$Number%03d$.m4s"/>
+      <Representation id="aac" bandwidth="320000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        let list = parse_manifest(mpd).unwrap();
+        assert_eq!(list.init_url, "https://cdn.example.com/audio/aac/init.mp4");
+        // 60.5 s in 4 s segments, the last one shorter
+        assert_eq!(list.segments.len(), 16);
+        assert_eq!(
+            list.segments[0].url,
+            "https://cdn.example.com/audio/aac/seg-000.m4s"
+        );
+        assert_eq!(list.segments[15].duration, 500);
+        assert_eq!(list.codecs, None);
+        assert!((list.duration_secs() - 60.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_fill_template() {
+        assert_eq!(
+            fill_template(
+                "$Bandwidth$/$Time$-$Number%05d$$$.mp4",
+                "x",
+                "96000",
+                7,
+                1234
+            )
+            .unwrap(),
+            "96000/1234-00007$.mp4"
+        );
+        assert!(fill_template("$Foo$.mp4", "x", "1", 1, 0).is_err());
+        assert!(fill_template("$Number.mp4", "x", "1", 1, 0).is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("PT3M25.5S"), Some(205.5));
+        assert_eq!(parse_duration("PT1H0M1S"), Some(3601.0));
+        assert_eq!(parse_duration("PT12"), None);
+        assert_eq!(parse_duration("P1D"), None);
+    }
+}
@@ -1,119 +1,187 @@
 use anyhow::{Context, Result};
+use base64::Engine;
 use bytes::Bytes;
 use futures::stream::{self, StreamExt};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use tidlers::client::{
     TidalClient,
-    models::track::{ManifestType, Track, TrackPlaybackInfoPostPaywallResponse},
+    models::{
+        album::Album,
+        artist::ArtistAlbumsFilter,
+        media::MediaItem,
+        track::{ManifestType, Track, TrackPlaybackInfoPostPaywallResponse},
+        video::{Video, VideoPlaybackInfo},
+    },
 };
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::QualityArg;
+use crate::archive::{self, Archive};
+use crate::auth::save_session;
+use crate::cover::{Cover, CoverCache, CoverSize};
+use crate::dash::{self, SegmentList};
+use crate::input::Input;
+use crate::metadata::TrackMetadata;
+use crate::remux;
+use crate::tagging;
+use crate::template::{Naming, TemplateValues, today};
+use crate::types::{AlbumList, DashContainer, MediaType, ReleaseFilter, VideoQuality};
+use crate::verify;
+
+mod concurrency;
+mod favorites;
+mod limiter;
+mod mirrors;
+mod parts;
+mod quota;
+mod timeouts;
+mod video;
+
+use concurrency::Concurrency;
+use limiter::{Failure, HttpError, RateLimiter, backoff, classify, retry_delay};
+use mirrors::{Mirrors, is_mirror_failure};
+use parts::{ResumeState, finish_part, parse_content_range, part_path};
+use quota::{Quota, QuotaReached, TokenBucket};
+pub use timeouts::Timeouts;
+
+/// Attempts per DASH segment before the whole track fails
+const SEGMENT_ATTEMPTS: u32 = 3;
+
+/// Session refreshes per track after 401 or 403 responses, on top of its attempts
+const SESSION_REFRESHES: u32 = 3;
+
+/// How long prefetched playback info is used before its stream URLs are fetched again
+const PLAYBACK_INFO_MAX_AGE: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// How far the length of a DASH manifest may differ from the track length
+const DURATION_TOLERANCE_SECS: f64 = 2.0;
 
 /// Struct for handling all download operations
 pub struct Downloader {
     output_dir: PathBuf,
     http_client: reqwest::Client,
-    max_parallel: usize,
+    /// Tracks downloaded at once
+    tracks: Concurrency,
+    /// DASH and video segments fetched at once, across all tracks
+    segments: Concurrency,
+    quality: QualityArg,
+    covers: CoverCache,
+    cover_file: String,
+    naming: Naming,
+    /// `None` skips videos in albums, playlists and mixes
+    video_quality: Option<VideoQuality>,
+    dash_container: DashContainer,
+    verify: bool,
+    multi_progress: MultiProgress,
+    /// Pauses TIDAL API calls while the API is rate limiting
+    api_limiter: Arc<RateLimiter>,
+    /// Playback info requests per second
+    api_rate: TokenBucket,
+    /// Tracks whose playback info is fetched ahead of their download
+    prefetch: usize,
+    /// Pauses media downloads while the CDN is rate limiting
+    cdn_limiter: Arc<RateLimiter>,
+    bandwidth: Option<TokenBucket>,
+    quota: Quota,
+    timeouts: Timeouts,
+    /// Health of the CDN hosts offered by track manifests
+    mirrors: Mirrors,
+    session_file: PathBuf,
+    /// Completed tracks, which are skipped by their ID
+    archive: Archive,
+    /// Bumped with every refresh of the access token during the run
+    session_generation: AtomicU64,
+    /// Videos left out of albums, playlists and mixes while planning, for the summary
+    skipped_videos: AtomicUsize,
 }
 
-struct DownloadSummary {
-    downloaded: usize,
-    skipped: usize,
-    failed: Vec<(String, anyhow::Error)>,
+/// Download settings chosen on the command line
+pub struct DownloadOptions {
+    pub max_parallel: usize,
+    pub segment_parallel: usize,
+    /// Adapt both limits to the server, with the values above as maximums
+    pub adaptive: bool,
+    pub quality: QualityArg,
+    pub cover_size: Option<CoverSize>,
+    pub cover_file: String,
+    /// `None` skips videos in albums, playlists and mixes
+    pub video_quality: Option<VideoQuality>,
+    pub dash_container: DashContainer,
+    /// Check every track file after downloading it
+    pub verify: bool,
+    /// Combined download rate of all tracks in bytes per second
+    pub limit_rate: Option<u64>,
+    /// Tracks and videos to download in this run, not counting skipped ones
+    pub max_downloads: Option<u64>,
+    /// Bytes to download in this run
+    pub max_bytes: Option<u64>,
+    pub timeouts: Timeouts,
+    /// Where a session refreshed during the run is saved
+    pub session_file: PathBuf,
+    pub archive: Archive,
+    /// Tracks whose playback info is fetched ahead of their download
+    pub prefetch: usize,
+    /// Playback info requests per second
+    pub api_rate: u64,
 }
 
-// Rate limiting state shared across all downloads
-struct RateLimitState {
-    is_rate_limited: AtomicBool,
-    consecutive_errors: AtomicU64,
-    last_backoff_time: Arc<tokio::sync::Mutex<Option<std::time::Instant>>>,
-    rate_limit_lock: Arc<tokio::sync::Mutex<()>>,
-    multi_progress: Arc<tokio::sync::Mutex<Option<MultiProgress>>>,
+/// A track or video queued for download, with its location already resolved
+struct TrackJob {
+    item: JobItem,
+    dir: PathBuf,
+    base_name: String,
+    label: String,
 }
 
-impl RateLimitState {
-    fn new() -> Arc<Self> {
-        Arc::new(Self {
-            is_rate_limited: AtomicBool::new(false),
-            consecutive_errors: AtomicU64::new(0),
-            last_backoff_time: Arc::new(tokio::sync::Mutex::new(None)),
-            rate_limit_lock: Arc::new(tokio::sync::Mutex::new(())),
-            multi_progress: Arc::new(tokio::sync::Mutex::new(None)),
-        })
-    }
-
-    async fn set_multi_progress(&self, mp: MultiProgress) {
-        let mut guard = self.multi_progress.lock().await;
-        *guard = Some(mp);
-    }
-
-    async fn on_error(&self) {
-        let errors = self.consecutive_errors.fetch_add(1, Ordering::SeqCst) + 1;
-
-        // If we hit 3 consecutive errors, trigger rate limit backoff
-        if errors >= 3 {
-            // Use lock to ensure only one thread prints the message
-            let _guard = self.rate_limit_lock.lock().await;
-
-            // Check again after acquiring lock
-            if !self.is_rate_limited.swap(true, Ordering::SeqCst) {
-                // Suspend multi-progress to stop all updates
-                // if let Some(mp) = self.multi_progress.lock().await.as_ref() {
-                //     mp.suspend(|| {
-                //         println!("\nrate limit detected! pausing all downloads for 5 seconds...");
-                //     });
-                // } else {
-                //     println!("\nrate limit detected! pausing all downloads for 5 seconds...");
-                // }
-                let mut last_time = self.last_backoff_time.lock().await;
-                *last_time = Some(std::time::Instant::now());
-            }
-        }
-    }
+enum JobItem {
+    Track {
+        track: Box<Track>,
+        metadata: TrackMetadata,
+        quality: QualityArg,
+    },
+    Video(Video),
+}
 
-    async fn on_success(&self) {
-        // Only reset if not rate limited
-        if !self.is_rate_limited.load(Ordering::SeqCst) {
-            self.consecutive_errors.store(0, Ordering::SeqCst);
+impl JobItem {
+    fn id(&self) -> u64 {
+        match self {
+            JobItem::Track { track, .. } => track.id,
+            JobItem::Video(video) => video.id,
         }
     }
+}
 
-    async fn wait_if_rate_limited(&self) {
-        if self.is_rate_limited.load(Ordering::SeqCst) {
-            // Use lock to ensure only one thread does the wait and reset
-            let _guard = self.rate_limit_lock.lock().await;
-
-            // Check again after acquiring lock
-            if self.is_rate_limited.load(Ordering::SeqCst) {
-                let mut last_time = self.last_backoff_time.lock().await;
-
-                if let Some(backoff_start) = *last_time {
-                    let elapsed = backoff_start.elapsed();
-                    let backoff_duration = std::time::Duration::from_secs(5);
+enum PlaybackInfo {
+    Track(TrackPlaybackInfoPostPaywallResponse),
+    Video(VideoPlaybackInfo),
+}
 
-                    if elapsed < backoff_duration {
-                        let remaining = backoff_duration - elapsed;
-                        drop(last_time); // Release lock before sleeping
-                        tokio::time::sleep(remaining).await;
-                        last_time = self.last_backoff_time.lock().await;
-                    }
+/// The outcome of a playback info request
+struct Fetched {
+    result: Result<PlaybackInfo>,
+    fetched_at: std::time::Instant,
+    /// Session generation the request was made with
+    session: u64,
+}
 
-                    // Reset rate limit state
-                    *last_time = None;
-                    self.is_rate_limited.store(false, Ordering::SeqCst);
-                    self.consecutive_errors.store(0, Ordering::SeqCst);
-                    // if let Some(mp) = self.multi_progress.lock().await.as_ref() {
-                    //     mp.suspend(|| {
-                    //         println!("resuming downloads...");
-                    //     });
-                    // } else {
-                    //     println!("resuming downloads...");
-                    // }
-                }
-            }
-        }
-    }
+struct DownloadSummary {
+    downloaded: usize,
+    skipped: usize,
+    failed: Vec<(String, anyhow::Error)>,
+    /// Tracks that kept failing verification after all attempts
+    corrupt: Vec<(String, anyhow::Error)>,
+    /// Tracks left for the next run because a download limit was reached
+    stopped: usize,
+    /// Videos left out because of `--video-quality none`
+    skipped_videos: usize,
+    /// How many downloads ran at once
+    concurrency: Vec<String>,
+    /// How the CDN mirrors fared, if any of them failed
+    mirrors: Vec<String>,
 }
 
 impl DownloadSummary {
@@ -122,6 +190,11 @@ impl DownloadSummary {
             downloaded: 0,
             skipped: 0,
             failed: Vec::new(),
+            corrupt: Vec::new(),
+            stopped: 0,
+            skipped_videos: 0,
+            concurrency: Vec::new(),
+            mirrors: Vec::new(),
         }
     }
 
@@ -131,6 +204,10 @@ impl DownloadSummary {
             match result {
                 Ok(true) => summary.downloaded += 1,
                 Ok(false) => summary.skipped += 1,
+                Err(e) if e.is::<QuotaReached>() => summary.stopped += 1,
+                Err(e) if e.downcast_ref::<VerificationFailed>().is_some() => {
+                    summary.corrupt.push((track_name, e))
+                }
                 Err(e) => summary.failed.push((track_name, e)),
             }
         }
@@ -143,107 +220,281 @@ impl DownloadSummary {
         if self.skipped > 0 {
             println!("  skipped: {} (already exist)", self.skipped);
         }
+        if self.skipped_videos > 0 {
+            println!(
+                "  skipped videos: {} (--video-quality none)",
+                self.skipped_videos
+            );
+        }
         if !self.failed.is_empty() {
             println!("  failed: {}", self.failed.len());
             for track in &self.failed {
                 println!("    - {} ({})", track.0, track.1.to_string());
             }
         }
+        if !self.corrupt.is_empty() {
+            println!("  failed verification: {}", self.corrupt.len());
+            for (track, e) in &self.corrupt {
+                println!("    - {} ({:#})", track, e);
+            }
+        }
+        if self.stopped > 0 {
+            println!(
+                "  left for the next run: {} (download limit reached)",
+                self.stopped
+            );
+        }
+        if !self.concurrency.is_empty() {
+            println!("  concurrency:");
+            for limit in &self.concurrency {
+                println!("    {}", limit);
+            }
+        }
+        if !self.mirrors.is_empty() {
+            println!("  mirrors:");
+            for mirror in &self.mirrors {
+                println!("    {}", mirror);
+            }
+        }
     }
 }
 
 impl Downloader {
-    pub fn new(output_dir: PathBuf, max_parallel: usize) -> Self {
-        Self {
+    pub fn new(output_dir: PathBuf, naming: Naming, options: DownloadOptions) -> Result<Self> {
+        let http_client = reqwest::Client::builder()
+            .connect_timeout(options.timeouts.connect)
+            .build()
+            .context("Failed to create HTTP client")?;
+        let multi_progress = MultiProgress::new();
+        Ok(Self {
             output_dir,
-            http_client: reqwest::Client::new(),
-            max_parallel,
-        }
+            http_client,
+            tracks: concurrency(options.adaptive, "tracks", options.max_parallel),
+            segments: concurrency(options.adaptive, "segments", options.segment_parallel),
+            quality: options.quality,
+            covers: CoverCache::new(options.cover_size, options.timeouts),
+            cover_file: options.cover_file,
+            naming,
+            video_quality: options.video_quality,
+            dash_container: options.dash_container,
+            verify: options.verify,
+            bandwidth: options.limit_rate.map(TokenBucket::new),
+            quota: Quota::new(options.max_downloads, options.max_bytes),
+            timeouts: options.timeouts,
+            mirrors: Mirrors::default(),
+            session_file: options.session_file,
+            archive: options.archive,
+            session_generation: AtomicU64::new(0),
+            skipped_videos: AtomicUsize::new(0),
+            api_limiter: RateLimiter::new("TIDAL API", multi_progress.clone()),
+            api_rate: TokenBucket::new(options.api_rate),
+            prefetch: options.prefetch,
+            cdn_limiter: RateLimiter::new("TIDAL CDN", multi_progress.clone()),
+            multi_progress,
+        })
     }
 
-    pub async fn download_track(&self, client: &mut TidalClient, track_id: &str) -> Result<()> {
-        let track = client
-            .get_track(track_id.to_string())
-            .await
-            .context("Failed to get track info")?;
-
-        println!("track: {}", track.title);
-        println!("artist: {}", track.artist.name);
-        println!("album: {}", track.album.title);
+    /// Downloads everything in `inputs` through one shared worker pool
+    ///
+    /// Inputs that can't be resolved are reported as failures in the combined summary.
+    pub async fn download_inputs(
+        &self,
+        client: &mut TidalClient,
+        inputs: &[Input],
+        filter: &ReleaseFilter,
+    ) -> Result<()> {
+        let mut jobs = Vec::new();
+        let mut failed = Vec::new();
+
+        for input in inputs {
+            println!("\ndownloading {} {}...", input.media_type, input.id);
+            match self.prepare_input(client, input, filter).await {
+                Ok(input_jobs) => jobs.extend(input_jobs),
+                Err(e) => {
+                    println!("skipping {} {}: {:#}", input.media_type, input.id, e);
+                    failed.push((format!("{} {}", input.media_type, input.id), e));
+                }
+            }
+        }
 
-        let playback_info = client
-            .get_track_postpaywall_playback_info(track_id.to_string())
-            .await
-            .context("Failed to get playback info")?;
+        let mut summary = self.download_tracks_parallel(client, jobs).await;
+        failed.append(&mut summary.failed);
+        summary.failed = failed;
+        summary.print();
+        Ok(())
+    }
 
-        let pb = ProgressBar::new_spinner();
-        pb.set_style(
-            ProgressStyle::default_spinner()
-                .template("{spinner:.green} [{elapsed_precise}] {msg}")
-                .unwrap(),
-        );
-        pb.set_message("Downloading...");
+    /// Fetches the info of one input and plans all of its tracks and videos
+    async fn prepare_input(
+        &self,
+        client: &mut TidalClient,
+        input: &Input,
+        filter: &ReleaseFilter,
+    ) -> Result<Vec<TrackJob>> {
+        let id = input.id.as_str();
+        let quality = input.quality;
+
+        match input.media_type {
+            MediaType::Track => {
+                let track = client
+                    .get_track(id.to_string())
+                    .await
+                    .context("Failed to get track info")?;
+
+                println!("track: {}", track.title);
+                println!("artist: {}", track.artist.name);
+                println!("album: {}", track.album.title);
+
+                Ok(vec![self.single_track_job(track, quality)])
+            }
+            MediaType::Album => {
+                let album = client
+                    .get_album(id.to_string())
+                    .await
+                    .context("Failed to get album info")?;
 
-        let was_downloaded = self
-            .download_track_with_info_pb(&track, &playback_info, &self.output_dir, Some(&pb))
-            .await?;
+                println!("album: {}", album.title);
+                println!("artist: {}", album.artist.name);
+                println!("tracks: {}", album.number_of_tracks);
 
-        if was_downloaded {
-            pb.finish_with_message("✓ Downloaded");
-        } else {
-            pb.finish_with_message("○ Already exists");
+                self.prepare_album(client, &album, quality).await
+            }
+            MediaType::Playlist => self.prepare_playlist(client, id, quality).await,
+            MediaType::Artist => self.prepare_artist(client, id, filter, quality).await,
+            MediaType::Mix => self.prepare_mix(client, id, quality).await,
+            MediaType::Video => Ok(vec![self.prepare_video(client, id).await?]),
         }
-
-        Ok(())
     }
 
-    pub async fn download_album(&self, client: &mut TidalClient, album_id: &str) -> Result<()> {
-        let album = client
-            .get_album(album_id.to_string())
+    /// Lists an artist's releases and plans the tracks of every release that passes `filter`
+    async fn prepare_artist(
+        &self,
+        client: &mut TidalClient,
+        artist_id: &str,
+        filter: &ReleaseFilter,
+        quality: QualityArg,
+    ) -> Result<Vec<TrackJob>> {
+        let artist = client
+            .get_artist(artist_id.to_string())
             .await
-            .context("Failed to get album info")?;
+            .context("Failed to get artist info")?;
+
+        println!("artist: {}", artist.name);
+
+        // fetch every requested release list (handles pagination)
+        let mut releases: Vec<Album> = Vec::new();
+        for list in filter.album_lists() {
+            let api_filter = match list {
+                AlbumList::Albums => None,
+                AlbumList::EpsAndSingles => Some(ArtistAlbumsFilter::EpsAndSingles),
+                AlbumList::Compilations => Some(ArtistAlbumsFilter::Compilations),
+            };
+            let mut offset = 0;
+            let limit = 100;
+
+            loop {
+                let page = client
+                    .get_artist_albums(artist_id.to_string(), api_filter, Some(limit), Some(offset))
+                    .await
+                    .context("Failed to get artist albums")?;
+
+                let fetched = page.items.len();
+                for album in page.items {
+                    if filter.matches(list, &album) && !releases.iter().any(|r| r.id == album.id) {
+                        releases.push(album);
+                    }
+                }
+
+                offset += limit;
+                if fetched == 0 || offset as usize >= page.total_number_of_items as usize {
+                    break;
+                }
+            }
+        }
 
-        println!("album: {}", album.title);
-        println!("artist: {}", album.artist.name);
-        println!("tracks: {}", album.number_of_tracks);
+        println!("releases: {}", releases.len());
+
+        // every release gets its own album directory, but all tracks share one worker pool
+        let mut jobs = Vec::new();
+        for album in &releases {
+            println!(
+                "  {} ({})",
+                album.title,
+                year(album.release_date.as_deref())
+            );
+            match self.prepare_album(client, album, quality).await {
+                Ok(album_jobs) => jobs.extend(album_jobs),
+                Err(e) => println!("    skipping release: {:#}", e),
+            }
+        }
 
-        let album_dir = self.output_dir.join(sanitize_filename::sanitize(format!(
-            "{} - {}",
-            album.artist.name, album.title
-        )));
+        Ok(jobs)
+    }
+
+    /// Creates the album directory, saves its cover and plans all of its tracks
+    async fn prepare_album(
+        &self,
+        client: &mut TidalClient,
+        album: &Album,
+        quality: QualityArg,
+    ) -> Result<Vec<TrackJob>> {
+        let album_dir = self
+            .output_dir
+            .join(self.naming.album.render(&album_values(album)));
         std::fs::create_dir_all(&album_dir).context("Failed to create album directory")?;
 
+        // fetch the cover up front so every track reuses the cached copy
+        if let Some(cover_id) = album.cover.as_deref() {
+            match self.get_cover(Some(cover_id)).await {
+                Some(cover) => {
+                    let cover_path = album_dir.join(&self.cover_file);
+                    if !cover_path.exists() {
+                        std::fs::write(&cover_path, &cover.data)
+                            .context("Failed to save album cover")?;
+                    }
+                }
+                None if self.covers.is_enabled() => println!("could not fetch album cover"),
+                None => {}
+            }
+        }
+
         // fetch all tracks from the album (handles pagination)
-        let mut all_tracks = Vec::new();
+        let mut all_items = Vec::new();
         let mut offset = 0;
         let limit = 100;
 
         loop {
             let items = client
-                .get_album_items(album_id.to_string(), Some(limit), Some(offset))
+                .get_album_items(album.id.to_string(), Some(limit), Some(offset))
                 .await
                 .context("Failed to get album tracks")?;
 
             for item in items.items {
-                all_tracks.push(item.item);
+                all_items.push(item.item);
             }
 
-            if all_tracks.len() >= items.total_number_of_items as usize {
+            if all_items.len() >= items.total_number_of_items as usize {
                 break;
             }
             offset += limit;
         }
 
-        self.download_tracks_parallel(
-            client, all_tracks, &album_dir, false, // use original track numbers
-        )
-        .await
+        Ok(self.plan_tracks(
+            all_items,
+            Some(album),
+            &album_dir,
+            false, // use original track numbers
+            quality,
+        ))
     }
-    pub async fn download_playlist(
+
+    /// Creates the playlist directory and plans all of its tracks
+    async fn prepare_playlist(
         &self,
         client: &mut TidalClient,
         playlist_id: &str,
-    ) -> Result<()> {
+        quality: QualityArg,
+    ) -> Result<Vec<TrackJob>> {
         let playlist = client
             .get_playlist(playlist_id.to_string())
             .await
@@ -253,14 +504,16 @@ impl Downloader {
         println!("creator: {}", playlist.creator.id);
         println!("tracks: {}", playlist.number_of_tracks);
 
-        let playlist_dir = self.output_dir.join(sanitize_filename::sanitize(format!(
-            "{}-playlist",
-            playlist.title
-        )));
+        let values = TemplateValues::from([
+            ("playlist", playlist.title.clone()),
+            ("tidal_id", playlist_id.to_string()),
+            ("date", today()),
+        ]);
+        let playlist_dir = self.output_dir.join(self.naming.playlist.render(&values));
         std::fs::create_dir_all(&playlist_dir).context("Failed to create playlist directory")?;
 
         // fetch all tracks from the playlist (handles pagination)
-        let mut all_tracks = Vec::new();
+        let mut all_items = Vec::new();
         let mut offset = 0;
         let limit = 100;
 
@@ -271,72 +524,249 @@ impl Downloader {
                 .context("Failed to get playlist tracks")?;
 
             for item in items.items {
-                all_tracks.push(item.item);
+                all_items.push(item.item);
             }
 
-            if all_tracks.len() >= items.total_number_of_items as usize {
+            if all_items.len() >= items.total_number_of_items as usize {
                 break;
             }
             offset += limit;
         }
 
-        self.download_tracks_parallel(
-            client,
-            all_tracks,
+        Ok(self.plan_tracks(
+            all_items,
+            None,
             &playlist_dir,
             true, // use playlist position as track number
-        )
-        .await
+            quality,
+        ))
     }
 
-    async fn download_tracks_parallel(
+    /// Creates the mix directory and plans all of its tracks
+    async fn prepare_mix(
         &self,
         client: &mut TidalClient,
-        tracks: Vec<Track>,
-        output_dir: &PathBuf,
+        mix_id: &str,
+        quality: QualityArg,
+    ) -> Result<Vec<TrackJob>> {
+        let mix = client
+            .get_mix(mix_id.to_string())
+            .await
+            .context("Failed to get mix info")?;
+
+        println!("mix: {}", mix.title);
+        if !mix.sub_title.is_empty() {
+            println!("description: {}", mix.sub_title);
+        }
+
+        let values = TemplateValues::from([
+            ("mix", mix.title.clone()),
+            ("tidal_id", mix_id.to_string()),
+            ("date", today()),
+        ]);
+        let mix_dir = self.output_dir.join(self.naming.mix.render(&values));
+        std::fs::create_dir_all(&mix_dir).context("Failed to create mix directory")?;
+
+        // fetch all tracks from the mix (handles pagination)
+        let mut all_items = Vec::new();
+        let mut offset = 0;
+        let limit = 100;
+
+        loop {
+            let items = client
+                .get_mix_items(mix_id.to_string(), Some(limit), Some(offset))
+                .await
+                .context("Failed to get mix tracks")?;
+
+            for item in items.items {
+                all_items.push(item.item);
+            }
+
+            if all_items.len() >= items.total_number_of_items as usize {
+                break;
+            }
+            offset += limit;
+        }
+
+        println!("items: {}", all_items.len());
+
+        // mix position is used as the track number
+        Ok(self.plan_tracks(all_items, None, &mix_dir, true, quality))
+    }
+
+    /// Resolves the output location of every track and video in a collection
+    fn plan_tracks(
+        &self,
+        items: Vec<MediaItem>,
+        album: Option<&Album>,
+        output_dir: &Path,
         use_index_as_track_number: bool,
-    ) -> Result<()> {
+        quality: QualityArg,
+    ) -> Vec<TrackJob> {
+        let multi_disc = items.iter().any(|item| match item {
+            MediaItem::Track(track) => track.volume_number > 1,
+            MediaItem::Video(video) => video.volume_number > 1,
+        });
+        let template = self
+            .naming
+            .track_template(use_index_as_track_number, multi_disc);
+
+        items
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, item)| {
+                let (values, item) = match item {
+                    MediaItem::Track(track) => {
+                        let metadata = TrackMetadata::new(&track, album);
+                        let position = if use_index_as_track_number {
+                            (index + 1) as u32
+                        } else {
+                            track.track_number
+                        };
+                        let values = track_values(&track, &metadata, position, quality);
+                        let track = Box::new(track);
+                        let item = JobItem::Track {
+                            track,
+                            metadata,
+                            quality,
+                        };
+                        (values, item)
+                    }
+                    MediaItem::Video(video) => {
+                        if self.video_quality.is_none() {
+                            self.skipped_videos.fetch_add(1, Ordering::SeqCst);
+                            return None;
+                        }
+                        let position = if use_index_as_track_number {
+                            (index + 1) as u32
+                        } else {
+                            video.track_number
+                        };
+                        (video_values(&video, position), JobItem::Video(video))
+                    }
+                };
+
+                let relative = template.render(&values);
+                let (dir, base_name) = split_track_path(output_dir, &relative, item.id());
+
+                Some(TrackJob {
+                    label: relative.display().to_string(),
+                    item,
+                    dir,
+                    base_name,
+                })
+            })
+            .collect()
+    }
+
+    async fn download_tracks_parallel(
+        &self,
+        client: &mut TidalClient,
+        jobs: Vec<TrackJob>,
+    ) -> DownloadSummary {
         println!(
             "\ndownloading {} tracks in parallel (max {})...\n",
-            tracks.len(),
-            self.max_parallel
+            jobs.len(),
+            self.tracks.max()
         );
 
+        // check every file location up front so name collisions are caught
+        // before anything is written
+        let mut claimed: HashMap<PathBuf, String> = HashMap::new();
+        let jobs: Vec<_> = jobs
+            .into_iter()
+            .map(|job| {
+                let path = job.dir.join(&job.base_name);
+                let collision = match claimed.get(&path) {
+                    Some(owner) => Some(format!(
+                        "File name collides with track {} ({})",
+                        owner, job.label
+                    )),
+                    None => {
+                        claimed.insert(path, job.item.id().to_string());
+                        None
+                    }
+                };
+                (job, collision)
+            })
+            .collect();
+
         let downloader = Arc::new(self);
         let client = Arc::new(tokio::sync::Mutex::new(client));
-        let rate_limit_state = RateLimitState::new();
+        let multi_progress = &self.multi_progress;
+
+        // the metadata stage resolves playback info ahead of the downloads, so workers
+        // don't queue for the client between tracks; the channel bounds how far ahead
+        let (resolved_tx, mut resolved_rx) = tokio::sync::mpsc::channel(self.prefetch.max(1));
+        let prefetch = {
+            let downloader = Arc::clone(&downloader);
+            let client = Arc::clone(&client);
+            async move {
+                for (job, collision) in jobs {
+                    let needed = collision.is_none()
+                        && !downloader.quota.is_reached()
+                        && !downloader.is_downloaded(&job);
+                    let fetched = if needed {
+                        Some(downloader.fetch_playback_info(&client, &job.item).await)
+                    } else {
+                        None
+                    };
+                    if resolved_tx.send((job, collision, fetched)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        };
+        let resolved = stream::poll_fn(|cx| resolved_rx.poll_recv(cx));
 
-        // Create multi-progress bar
-        let multi_progress = MultiProgress::new();
-        rate_limit_state
-            .set_multi_progress(multi_progress.clone())
-            .await;
+        let downloads = resolved
+            .map(async |(job, collision, mut prefetched)| {
+                // tracks that exist already are skipped without any API calls
+                if collision.is_none() && prefetched.is_none() && downloader.is_downloaded(&job) {
+                    let pb = multi_progress.add(ProgressBar::new_spinner());
+                    pb.finish_with_message(format!("○ {}", job.label));
+                    return (job.label, Ok(false));
+                }
+
+                let TrackJob {
+                    mut item,
+                    dir: track_dir,
+                    base_name,
+                    label: format_str,
+                } = job;
+
+                if let Some(collision) = collision {
+                    let pb = multi_progress.add(ProgressBar::new_spinner());
+                    pb.finish_with_message(format!("✗ {} (name collision)", format_str));
+                    return (format_str, Err(anyhow::anyhow!(collision)));
+                }
 
-        let results = stream::iter(tracks.into_iter().enumerate())
-            .map(async |(index, track)| {
                 let downloader = Arc::clone(&downloader);
                 let client = Arc::clone(&client);
-                let output_dir = output_dir.clone();
-                let rate_limit_state = Arc::clone(&rate_limit_state);
-                let multi_progress = multi_progress.clone();
+                let _permit = downloader.tracks.acquire().await;
+                if downloader.quota.is_reached() {
+                    return (format_str, Err(QuotaReached.into()));
+                }
+                if let JobItem::Track {
+                    track, metadata, ..
+                } = &mut item
+                {
+                    metadata.cover = downloader.get_cover(track.album.cover.as_deref()).await;
+                }
                 let mut attempt = 0;
                 let max_attempts = 10;
+                let mut refreshes = 0;
 
                 loop {
-                    // Wait if rate limited BEFORE creating progress bar
-                    rate_limit_state.wait_if_rate_limited().await;
-
-                    let track_number = if use_index_as_track_number {
-                        (index + 1) as u32
-                    } else {
-                        track.track_number
-                    };
-
-                    let format_str = if use_index_as_track_number {
-                        format!("{:03} - {}", track_number, track.title)
-                    } else {
-                        format!("{:02} - {}", track_number, track.title)
+                    // stream URLs that waited too long for their turn may have expired,
+                    // and retries always start with fresh ones
+                    let fetched = match prefetched.take() {
+                        Some(fetched) if fetched.fetched_at.elapsed() < PLAYBACK_INFO_MAX_AGE => {
+                            fetched
+                        }
+                        _ => downloader.fetch_playback_info(&client, &item).await,
                     };
+                    let session = fetched.session;
 
                     let pb = multi_progress.add(ProgressBar::new_spinner());
                     pb.set_style(
@@ -346,33 +776,61 @@ impl Downloader {
                     );
                     pb.set_message(format!("{}", format_str));
 
-                    let track_id = track.id.to_string();
-                    let result = {
-                        let mut client_guard = client.lock().await;
-                        client_guard
-                            .get_track_postpaywall_playback_info(track_id)
-                            .await
-                    };
-
-                    match result {
+                    match fetched.result {
                         Ok(playback_info) => {
-                            rate_limit_state.on_success().await;
-
-                            let result = downloader
-                                .download_track_with_info_numbered_pb(
-                                    &track,
-                                    &playback_info,
-                                    &output_dir,
-                                    track_number,
-                                    Some(&pb),
-                                )
-                                .await;
+                            let result = match (&item, &playback_info) {
+                                (
+                                    JobItem::Track {
+                                        metadata, quality, ..
+                                    },
+                                    PlaybackInfo::Track(info),
+                                ) => {
+                                    downloader
+                                        .download_track_with_info_numbered_pb(
+                                            info,
+                                            metadata,
+                                            *quality,
+                                            &track_dir,
+                                            &base_name,
+                                            Some(&pb),
+                                        )
+                                        .await
+                                }
+                                (JobItem::Video(_), PlaybackInfo::Video(info)) => {
+                                    downloader
+                                        .download_video_pb(info, &track_dir, &base_name, Some(&pb))
+                                        .await
+                                }
+                                _ => unreachable!("playback info is fetched for the item's type"),
+                            };
 
                             if result.is_ok() {
                                 match result.as_ref().unwrap() {
                                     true => pb.finish_with_message(format!("✓ {}", format_str)),
                                     false => pb.finish_with_message(format!("○ {}", format_str)),
                                 }
+                            } else if result.as_ref().is_err_and(|e| e.is::<QuotaReached>()) {
+                                // the partial download is picked up by the next run
+                                pb.finish_with_message(format!(
+                                    "‖ {} (download limit reached)",
+                                    format_str
+                                ));
+                            } else if refreshes < SESSION_REFRESHES
+                                && result
+                                    .as_ref()
+                                    .is_err_and(|e| classify(e) == Failure::Unauthorized)
+                            {
+                                // the stream URLs expired, fresh ones come with the playback
+                                // info of the next round, which doesn't count as an attempt
+                                refreshes += 1;
+                                pb.finish_with_message(format!(
+                                    "↻ {} (access expired, refreshing...)",
+                                    format_str
+                                ));
+                                if let Err(e) = downloader.refresh_session(&client, session).await {
+                                    return (format_str, Err(e));
+                                }
+                                continue;
                             } else {
                                 pb.finish_with_message(format!(
                                     "✗ {} (attempt {}/{})",
@@ -380,21 +838,37 @@ impl Downloader {
                                     attempt + 1,
                                     max_attempts
                                 ));
-                                if attempt < max_attempts {
+                                // rate limits of the CDN were already recorded by the
+                                // requests that ran into them
+                                if let Err(e) = &result
+                                    && attempt < max_attempts
+                                    && let Some(delay) = track_retry_delay(e, attempt + 1)
+                                {
                                     attempt += 1;
-                                    // notify rate limit state of error
-                                    rate_limit_state.on_error().await;
+                                    downloader.tracks.on_error(e);
+                                    tokio::time::sleep(delay).await;
 
                                     continue;
-                                } else {
-                                    // notify rate limit state of error
-                                    rate_limit_state.on_error().await;
                                 }
                             }
 
                             return (format_str, result);
                         }
                         Err(e) => {
+                            if refreshes < SESSION_REFRESHES
+                                && classify(&e) == Failure::Unauthorized
+                            {
+                                refreshes += 1;
+                                pb.finish_with_message(format!(
+                                    "↻ {} (access expired, refreshing...)",
+                                    format_str
+                                ));
+                                if let Err(e) = downloader.refresh_session(&client, session).await {
+                                    return (format_str, Err(e));
+                                }
+                                continue;
+                            }
+
                             pb.finish_with_message(format!(
                                 "✗ {} (attempt {}/{}, retrying later...)",
                                 format_str,
@@ -402,61 +876,141 @@ impl Downloader {
                                 max_attempts
                             ));
 
-                            if attempt < max_attempts {
-                                attempt += 1;
+                            downloader.tracks.on_error(&e);
 
-                                // Notify rate limit state of error
-                                rate_limit_state.on_error().await;
+                            if attempt < max_attempts
+                                && let Some(delay) = retry_delay(&e, attempt + 1)
+                            {
+                                attempt += 1;
+                                tokio::time::sleep(delay).await;
 
                                 continue;
                             } else {
-                                // Notify rate limit state of error
-                                rate_limit_state.on_error().await;
-
-                                return (format_str, Err(e).context("Failed to get playback info"));
+                                return (format_str, Err(e));
                             }
                         }
                     }
                 }
             })
-            .buffer_unordered(self.max_parallel)
-            .collect::<Vec<_>>()
-            .await;
+            .buffer_unordered(self.tracks.max())
+            .collect::<Vec<_>>();
+        let ((), results) = tokio::join!(prefetch, downloads);
+
+        let mut summary = DownloadSummary::from_results(results);
+        summary.concurrency = vec![self.tracks.describe(), self.segments.describe()];
+        summary.mirrors = self.mirrors.describe();
+        summary.skipped_videos = self.skipped_videos.swap(0, Ordering::SeqCst);
+        summary
+    }
 
-        DownloadSummary::from_results(results).print();
-        Ok(())
+    /// Fetches the playback info of a track or video, which holds the stream URLs
+    async fn fetch_playback_info(
+        &self,
+        client: &tokio::sync::Mutex<&mut TidalClient>,
+        item: &JobItem,
+    ) -> Fetched {
+        self.api_rate.take(1).await;
+        self.api_limiter.wait().await;
+
+        let mut client = client.lock().await;
+        let session = self.session_generation.load(Ordering::SeqCst);
+        let started = std::time::Instant::now();
+        let item_id = item.id().to_string();
+        let result = match item {
+            JobItem::Track { quality, .. } => {
+                client.set_audio_quality((*quality).into());
+                client
+                    .get_track_postpaywall_playback_info(item_id)
+                    .await
+                    .map(PlaybackInfo::Track)
+            }
+            JobItem::Video(_) => client
+                .get_video_playback_info(item_id)
+                .await
+                .map(PlaybackInfo::Video),
+        };
+        drop(client);
+        self.tracks.on_latency(started.elapsed());
+
+        let result =
+            result.map_err(|e| anyhow::Error::new(e).context("Failed to get playback info"));
+        match &result {
+            Ok(_) => self.api_limiter.on_success(),
+            // a 429 pauses all API calls, not just this track's
+            Err(e) => self.api_limiter.on_error(e),
+        }
+
+        Fetched {
+            result,
+            fetched_at: std::time::Instant::now(),
+            session,
+        }
     }
-    async fn download_track_with_info_pb(
+
+    /// Refreshes the access token after TIDAL rejected a request as unauthorized
+    ///
+    /// `seen` is the session generation the rejected request was made with. If another
+    /// track refreshed the session in the meantime, its token is used as it is.
+    async fn refresh_session(
         &self,
-        track: &Track,
-        playback_info: &TrackPlaybackInfoPostPaywallResponse,
-        output_dir: &PathBuf,
-        pb: Option<&ProgressBar>,
-    ) -> Result<bool> {
-        self.download_track_with_info_numbered_pb(
-            track,
-            playback_info,
-            output_dir,
+        client: &tokio::sync::Mutex<&mut TidalClient>,
+        seen: u64,
+    ) -> Result<()> {
+        let mut client = client.lock().await;
+        if self.session_generation.load(Ordering::SeqCst) != seen {
+            return Ok(());
+        }
+
+        client
+            .refresh_access_token(true)
+            .await
+            .context("Failed to refresh access token")?;
+        self.session_generation.fetch_add(1, Ordering::SeqCst);
+        self.multi_progress
+            .suspend(|| save_session(&client, &self.session_file))
+    }
+    async fn get_cover(&self, cover_id: Option<&str>) -> Option<Cover> {
+        self.covers.get(&self.http_client, cover_id?).await
+    }
+
+    /// Plans a track downloaded on its own, outside of any album or playlist
+    fn single_track_job(&self, track: Track, quality: QualityArg) -> TrackJob {
+        let metadata = TrackMetadata::new(&track, None);
+        let template = self.naming.track_template(false, false);
+        let relative = template.render(&track_values(
+            &track,
+            &metadata,
             track.track_number,
-            pb,
-        )
-        .await
+            quality,
+        ));
+        let (dir, base_name) = split_track_path(&self.output_dir, &relative, track.id);
+
+        TrackJob {
+            label: relative.display().to_string(),
+            item: JobItem::Track {
+                track: Box::new(track),
+                metadata,
+                quality,
+            },
+            dir,
+            base_name,
+        }
     }
 
     async fn download_track_with_info_numbered_pb(
         &self,
-        track: &Track,
         playback_info: &TrackPlaybackInfoPostPaywallResponse,
-        output_dir: &PathBuf,
-        track_number: u32,
+        metadata: &TrackMetadata,
+        quality: QualityArg,
+        output_dir: &Path,
+        base_name: &str,
         pb: Option<&ProgressBar>,
     ) -> Result<bool> {
-        let extension = self.get_file_extension(playback_info);
-        let base_name = format!(
-            "{:03} - {}",
-            track_number,
-            sanitize_filename::sanitize(&track.title)
-        );
+        let segment_list = match &playback_info.manifest_parsed {
+            Some(ManifestType::Dash(_)) => Some(dash_segments(playback_info)?),
+            _ => None,
+        };
+        let extension = self.get_file_extension(playback_info, segment_list.as_ref());
 
         // check if file exists with current extension
         let output_path = output_dir.join(format!("{}.{}", base_name, extension));
@@ -464,6 +1018,75 @@ impl Downloader {
         if output_path.exists() {
             return Ok(false); // file was skipped
         }
+        let claim = self.quota.start_download()?;
+
+        std::fs::create_dir_all(output_dir).context("Failed to create track directory")?;
+
+        // the track only shows up under its final name once it is downloaded and tagged,
+        // so an interrupted run never leaves a truncated file that would be skipped later
+        let part_path = part_path(&output_path);
+        let result = match &playback_info.manifest_parsed {
+            Some(ManifestType::Dash(_)) => {
+                let segment_list = segment_list.as_ref().context("No DASH segment list")?;
+
+                // a manifest that doesn't cover the whole track is a preview or broken
+                let manifest_secs = segment_list.duration_secs();
+                if metadata.duration > 0
+                    && (manifest_secs - metadata.duration as f64).abs() > DURATION_TOLERANCE_SECS
+                {
+                    anyhow::bail!(
+                        "DASH manifest covers {:.1} s but the track is {} s long",
+                        manifest_secs,
+                        metadata.duration
+                    );
+                }
+
+                self.download_dash_track_pb(segment_list, &part_path, pb)
+                    .await
+            }
+            Some(ManifestType::Json(json_manifest)) => {
+                self.download_from_mirrors(&json_manifest.urls, &part_path, pb)
+                    .await
+            }
+            None => {
+                anyhow::bail!("No parsed manifest available");
+            }
+        };
+
+        // a failed download keeps its partial data so the next attempt can resume it
+        result?;
+
+        let result: Result<()> = async {
+            // DASH FLAC arrives as fragmented MP4, a .flac file has to be a native FLAC stream
+            if segment_list.is_some() && extension == "flac" {
+                if let Some(pb) = pb {
+                    pb.set_message("Remuxing to FLAC...");
+                }
+                remux::fmp4_file_to_flac(&part_path).context("Failed to remux FLAC stream")?;
+            }
+
+            if let Some(pb) = pb {
+                pb.set_message("Writing tags...");
+            }
+            tagging::write_tags(&part_path, metadata).context("Failed to write tags")?;
+
+            if self.verify {
+                if let Some(pb) = pb {
+                    pb.set_message("Verifying...");
+                }
+                verify_track(&part_path, metadata.duration)
+                    .await
+                    .context(VerificationFailed)?;
+            }
+
+            finish_part(&part_path, &output_path)
+        }
+        .await;
+
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&part_path);
+            return Err(e);
+        }
 
         // check if file exists with different extension (different quality already downloaded)
         let possible_extensions = ["m4a", "flac", "mp3"];
@@ -471,139 +1094,218 @@ impl Downloader {
             if ext != &extension {
                 let other_path = output_dir.join(format!("{}.{}", base_name, ext));
                 if other_path.exists() {
-                    // delete the old file to replace it with new quality
+                    // delete the old file now that the new quality is in place
                     std::fs::remove_file(&other_path)
                         .context("Failed to remove old file with different quality")?;
                 }
             }
         }
 
-        match &playback_info.manifest_parsed {
-            Some(ManifestType::Dash(dash)) => {
-                self.download_dash_track_pb(dash, &output_path, pb).await?;
-            }
-            Some(ManifestType::Json(json_manifest)) => {
-                if let Some(url) = json_manifest.urls.first() {
-                    self.download_file_pb(url, &output_path, pb).await?;
-                } else {
-                    anyhow::bail!("No URLs in manifest");
+        let codec = match &playback_info.manifest_parsed {
+            Some(ManifestType::Json(json_manifest)) => Some(json_manifest.codecs.clone()),
+            _ => segment_list.and_then(|list| list.codecs),
+        };
+        let entry = ArchiveEntry {
+            id: playback_info.track_id,
+            quality: received_quality(&playback_info.audio_quality, quality),
+            requested: quality_name(quality),
+            codec: codec.unwrap_or_else(|| extension.to_string()),
+        };
+        // the track is complete either way, it just won't be recognized after a rename
+        if let Err(e) = self.archive_track(entry, &output_path).await {
+            let _ = self
+                .multi_progress
+                .println(format!("! {}: {:#}", output_path.display(), e));
+        }
+
+        claim.complete();
+        Ok(true) // file was downloaded
+    }
+
+    /// Records a completed track in the download archive
+    async fn archive_track(&self, entry: ArchiveEntry, path: &Path) -> Result<()> {
+        let path = path.to_path_buf();
+        let entry = tokio::task::spawn_blocking(move || {
+            archive::Entry::for_file(
+                entry.id,
+                &path,
+                entry.quality,
+                Some(entry.requested),
+                &entry.codec,
+            )
+        })
+        .await
+        .context("Archive task failed")??;
+
+        self.archive
+            .record(entry)
+            .context("Failed to update download archive")
+    }
+
+    /// Downloads a file from the first of its mirrors that works, healthy hosts first
+    async fn download_from_mirrors(
+        &self,
+        urls: &[String],
+        output_path: &Path,
+        pb: Option<&ProgressBar>,
+    ) -> Result<()> {
+        let urls = self.mirrors.order(urls);
+        let mut last_error = None;
+
+        for (i, url) in urls.iter().enumerate() {
+            match self.download_file_pb(url, output_path, pb).await {
+                Ok(()) => {
+                    self.mirrors.on_success(url);
+                    return Ok(());
+                }
+                Err(e) if !is_mirror_failure(&e) => return Err(e),
+                Err(e) => {
+                    self.mirrors.on_failure(url);
+                    if let Some(pb) = pb
+                        && let Some(next) = urls.get(i + 1)
+                    {
+                        pb.set_message(format!(
+                            "{} failed, trying {}...",
+                            mirrors::host(url),
+                            mirrors::host(next)
+                        ));
+                    }
+                    last_error = Some(e);
                 }
-            }
-            None => {
-                anyhow::bail!("No parsed manifest available");
             }
         }
 
-        Ok(true) // file was downloaded
+        match last_error {
+            Some(e) if urls.len() > 1 => {
+                Err(e.context(format!("All {} mirrors failed", urls.len())))
+            }
+            Some(e) => Err(e),
+            None => anyhow::bail!("No URLs in manifest"),
+        }
     }
 
     async fn download_dash_track_pb(
         &self,
-        dash: &tidlers::client::models::track::DashManifest,
-        output_path: &PathBuf,
+        segment_list: &SegmentList,
+        output_path: &Path,
         pb: Option<&ProgressBar>,
     ) -> Result<()> {
-        // Step 1: Download initialization segment (required for DASH)
-        let init_data = if let Some(init_url) = dash.get_init_url() {
-            if let Some(pb) = pb {
-                pb.set_message("Downloading init segment...");
-            }
-            self.download_segment(init_url).await?
-        } else {
-            anyhow::bail!("No initialization segment found");
-        };
+        let urls: Vec<&str> = segment_list
+            .segments
+            .iter()
+            .map(|segment| segment.url.as_str())
+            .collect();
+        self.download_segments_pb(Some(&segment_list.init_url), &urls, output_path, pb)
+            .await
+    }
 
-        // Step 2: Download segments with adaptive discovery
-        // We'll download in batches and stop when we hit consecutive failures
-        let mut all_segments: Vec<(u32, Bytes)> = Vec::new();
-        let mut segment_num = 1;
-        let batch_size = 50; // Download 50 segments at a time
-        
-        loop {
-            // Prepare batch of segment URLs
-            let mut batch_urls = Vec::new();
-            for i in 0..batch_size {
-                if let Some(url) = dash.get_segment_url(segment_num + i) {
-                    batch_urls.push((segment_num + i, url));
-                } else {
-                    break;
+    /// Downloads an init segment and the media segments after it into one file
+    ///
+    /// Progress is saved after every segment, so an interrupted download continues after
+    /// the last segment that was written completely.
+    async fn download_segments_pb(
+        &self,
+        init_url: Option<&str>,
+        urls: &[&str],
+        output_path: &Path,
+        pb: Option<&ProgressBar>,
+    ) -> Result<()> {
+        // Step 1: Download the initialization segment, if the stream has one
+        let init_data = match init_url {
+            Some(init_url) => {
+                if let Some(pb) = pb {
+                    pb.set_message("Downloading init segment...");
                 }
+                self.download_segment_with_retries(init_url)
+                    .await
+                    .context("Failed to download init segment")?
             }
-            
-            if batch_urls.is_empty() {
-                break;
-            }
-            
-            if let Some(pb) = pb {
-                pb.set_message(format!("Downloading segments {}-{}...", segment_num, segment_num + batch_urls.len() as u32 - 1));
-            }
-            
-            // Download batch in parallel
-            let batch_results = stream::iter(batch_urls)
-                .map(|(num, url)| async move {
-                    match self.download_segment(&url).await {
-                        Ok(data) => Ok((num, data)),
-                        Err(e) => Err((num, e)),
-                    }
-                })
-                .buffer_unordered(20)
-                .collect::<Vec<_>>()
-                .await;
-            
-            // Check results
-            let mut consecutive_failures = 0;
-            let mut batch_segments = Vec::new();
-            
-            for result in batch_results {
-                match result {
-                    Ok((num, data)) => {
-                        batch_segments.push((num, data));
-                        consecutive_failures = 0;
-                    }
-                    Err(_) => {
-                        consecutive_failures += 1;
-                        if consecutive_failures >= 3 {
-                            break;
-                        }
-                    }
-                }
+            None => Bytes::new(),
+        };
+
+        // Step 2: Pick up after the last segment a previous attempt wrote completely
+        let resume = match ResumeState::load(output_path) {
+            // a different init segment means a different stream than the one on disk
+            Some(ResumeState::Segments { segments, bytes }) => {
+                starts_with(output_path, &init_data, bytes)
+                    .await
+                    .then_some((segments, bytes))
             }
-            
-            // If we got no segments in this batch, we're done
-            if batch_segments.is_empty() {
-                break;
+            _ => None,
+        };
+
+        let (mut file, mut written_segments, mut written_bytes) = match resume {
+            Some((segments, bytes)) => {
+                let mut file = tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .open(output_path)
+                    .await
+                    .context("Failed to open file")?;
+                // drop a segment that was only partly written
+                file.set_len(bytes).await.context("Failed to write file")?;
+                file.seek(std::io::SeekFrom::End(0))
+                    .await
+                    .context("Failed to write file")?;
+                (file, segments, bytes)
             }
-            
-            all_segments.extend(batch_segments);
-            segment_num += batch_size;
-            
-            // Stop if we hit too many failures
-            if consecutive_failures >= 3 {
-                break;
+            None => {
+                let mut file = tokio::fs::File::create(output_path)
+                    .await
+                    .context("Failed to create file")?;
+                file.write_all(&init_data)
+                    .await
+                    .context("Failed to write file")?;
+                (file, 0, init_data.len() as u64)
             }
-        }
+        };
 
+        let total = urls.len() as u32;
         if let Some(pb) = pb {
-            pb.set_message("Combining segments...");
+            pb.set_length(total as u64);
+            pb.set_position(written_segments as u64);
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} segments ({eta})")
+                    .unwrap()
+                    .progress_chars("#>-")
+            );
         }
 
-        // Step 3: Sort and combine
-        all_segments.sort_by_key(|(num, _)| *num);
-        
-        let total_size = init_data.len() + all_segments.iter().map(|(_, data)| data.len()).sum::<usize>();
-        let mut combined_data = Vec::with_capacity(total_size);
-        combined_data.extend_from_slice(&init_data);
-        
-        for (_, segment_data) in all_segments {
-            combined_data.extend_from_slice(&segment_data);
-        }
+        // Step 3: Download every segment listed in the manifest or playlist
+        // `buffered` yields segments in order while fetching ahead, so at most as many
+        // segments as the segment limit allows are held in memory while waiting for a slow one
+        let mut segments = stream::iter(urls.iter().zip(1..).skip(written_segments as usize))
+            .map(|(url, num)| async move {
+                let result = self.download_segment_with_retries(url).await;
+                (num, result)
+            })
+            .buffered(self.segments.max());
 
-        if let Some(pb) = pb {
-            pb.set_message("Writing to disk...");
+        while let Some((num, result)) = segments.next().await {
+            // a missing segment would leave a gap in the file
+            let data =
+                result.with_context(|| format!("Failed to download segment {}/{}", num, total))?;
+
+            file.write_all(&data)
+                .await
+                .context("Failed to write file")?;
+            file.flush().await.context("Failed to write file")?;
+
+            // the segment is on disk, remember it in case the download is interrupted
+            written_segments = num;
+            written_bytes += data.len() as u64;
+            ResumeState::Segments {
+                segments: written_segments,
+                bytes: written_bytes,
+            }
+            .save(output_path)?;
+
+            if let Some(pb) = pb {
+                pb.set_position(num as u64);
+            }
         }
 
-        // Write to file
-        std::fs::write(output_path, combined_data).context("Failed to write file")?;
+        ResumeState::remove(output_path);
 
         Ok(())
     }
@@ -611,80 +1313,284 @@ impl Downloader {
     async fn download_file_pb(
         &self,
         url: &str,
-        output_path: &PathBuf,
+        output_path: &Path,
         pb: Option<&ProgressBar>,
     ) -> Result<()> {
-        use futures::StreamExt;
-
-        let response = self
-            .http_client
-            .get(url)
-            .timeout(std::time::Duration::from_secs(60))
-            .send()
-            .await
-            .context("Failed to send request")?;
+        // resume a previous attempt if we know which CDN object its data came from
+        let previous = match ResumeState::load(output_path) {
+            Some(ResumeState::File {
+                etag,
+                last_modified,
+                ..
+            }) => {
+                let existing = std::fs::metadata(output_path).map_or(0, |m| m.len());
+                etag.or(last_modified)
+                    .filter(|_| existing > 0)
+                    .map(|validator| (existing, validator))
+            }
+            _ => None,
+        };
 
-        if !response.status().is_success() {
-            anyhow::bail!("HTTP {}", response.status());
+        let mut request = self.http_client.get(url);
+        if let Some((existing, validator)) = &previous {
+            // If-Range makes the server send the whole file if the object has changed
+            request = request
+                .header(reqwest::header::RANGE, format!("bytes={}-", existing))
+                .header(reqwest::header::IF_RANGE, validator);
         }
 
-        let total_size = response.content_length().unwrap_or(0);
+        self.cdn_limiter.wait().await;
+        let response = self.timeouts.send(request).await?;
 
-        if let Some(pb) = pb {
-            if total_size > 0 {
-                pb.set_length(total_size);
-                pb.set_style(
-                    ProgressStyle::default_bar()
-                        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                        .unwrap()
-                        .progress_chars("#>-")
-                );
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(reqwest::header::ETAG);
+        let last_modified = header(reqwest::header::LAST_MODIFIED);
+
+        let (resume_from, total_size) = match response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => {
+                let (existing, validator) = previous.context("Unexpected partial response")?;
+                let content_range = header(reqwest::header::CONTENT_RANGE)
+                    .and_then(|value| parse_content_range(&value));
+
+                // never append data of another object or at the wrong offset
+                let same_object = etag.as_ref().or(last_modified.as_ref()) == Some(&validator);
+                match content_range {
+                    Some((start, total)) if start == existing && same_object => (existing, total),
+                    _ => {
+                        ResumeState::remove(output_path);
+                        anyhow::bail!("Server response does not continue the partial download");
+                    }
+                }
+            }
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE => {
+                ResumeState::remove(output_path);
+                anyhow::bail!("Server rejected resuming the partial download");
+            }
+            status if status.is_success() => (0, response.content_length()),
+            _ => {
+                let error = HttpError::from_response(&response).into();
+                self.cdn_limiter.on_error(&error);
+                return Err(error);
+            }
+        };
+        self.cdn_limiter.on_success();
+
+        if let Some(pb) = pb
+            && let Some(total_size) = total_size
+        {
+            pb.set_length(total_size);
+            pb.set_position(resume_from);
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                    .unwrap()
+                    .progress_chars("#>-")
+            );
+        }
+
+        let file = if resume_from > 0 {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(output_path)
+                .await
+                .context("Failed to open file")?
+        } else {
+            tokio::fs::File::create(output_path)
+                .await
+                .context("Failed to create file")?
+        };
+        let mut writer = tokio::io::BufWriter::new(file);
+
+        // only resumable if a later response can be matched to this object
+        if etag.is_some() || last_modified.is_some() {
+            ResumeState::File {
+                etag,
+                last_modified,
+                total_size,
             }
+            .save(output_path)?;
+        } else {
+            ResumeState::remove(output_path);
         }
 
-        let mut downloaded: u64 = 0;
+        let mut downloaded = resume_from;
         let mut stream = response.bytes_stream();
-        let mut file_data = Vec::new();
+        let mut body = self.timeouts.body();
+
+        let result: Result<()> = async {
+            while let Some(chunk) = body.next(&mut stream).await? {
+                // checked before writing, so a file whose last chunk arrived is complete
+                self.quota.check_bytes()?;
+                writer
+                    .write_all(&chunk)
+                    .await
+                    .context("Failed to write file")?;
+                downloaded += chunk.len() as u64;
+                self.received(chunk.len() as u64).await;
+
+                if let Some(pb) = pb {
+                    pb.set_position(downloaded);
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        // keep what was received so far for the next attempt, a network error that ended
+        // the transfer stays the cause over a failed flush
+        let flushed = writer.flush().await.context("Failed to write file");
+        result?;
+        flushed?;
+
+        if let Some(total_size) = total_size
+            && downloaded != total_size
+        {
+            anyhow::bail!(
+                "Download incomplete: {} of {} bytes",
+                downloaded,
+                total_size
+            );
+        }
 
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.context("Failed to read chunk")?;
-            file_data.extend_from_slice(&chunk);
-            downloaded += chunk.len() as u64;
+        ResumeState::remove(output_path);
 
-            if let Some(pb) = pb {
-                pb.set_position(downloaded);
+        Ok(())
+    }
+
+    async fn download_segment_with_retries(&self, url: &str) -> Result<Bytes> {
+        let mut attempt = 1;
+        loop {
+            match self.download_segment(url).await {
+                Ok(data) => return Ok(data),
+                // expired URLs are replaced with fresh playback info by the track's worker
+                Err(e)
+                    if attempt >= SEGMENT_ATTEMPTS
+                        || e.is::<QuotaReached>()
+                        || classify(&e) == Failure::Unauthorized =>
+                {
+                    return Err(e);
+                }
+                Err(e) => match retry_delay(&e, attempt) {
+                    Some(delay) => {
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    None => return Err(e),
+                },
             }
         }
+    }
 
-        if let Some(pb) = pb {
-            pb.set_message("Writing to disk...");
+    async fn download_segment(&self, url: &str) -> Result<Bytes> {
+        self.quota.check_bytes()?;
+        self.cdn_limiter.wait().await;
+        let _permit = self.segments.acquire().await;
+        let started = std::time::Instant::now();
+
+        let result: Result<Bytes> = async {
+            let response = self.timeouts.send(self.http_client.get(url)).await?;
+
+            if !response.status().is_success() {
+                let error = HttpError::from_response(&response).into();
+                self.cdn_limiter.on_error(&error);
+                return Err(error);
+            }
+            self.cdn_limiter.on_success();
+
+            let mut data = Vec::with_capacity(response.content_length().unwrap_or(0) as usize);
+            let mut stream = response.bytes_stream();
+            let mut body = self.timeouts.body();
+            while let Some(chunk) = body.next(&mut stream).await? {
+                self.received(chunk.len() as u64).await;
+                data.extend_from_slice(&chunk);
+            }
+            Ok(Bytes::from(data))
         }
+        .await;
 
-        std::fs::write(output_path, file_data).context("Failed to write file")?;
+        match &result {
+            Ok(data) => {
+                self.segments.on_latency(started.elapsed());
+                self.segments.on_bytes(data.len() as u64);
+            }
+            Err(e) => self.segments.on_error(e),
+        }
+        result
+    }
 
-        Ok(())
+    /// Accounts for received media data: the bandwidth cap, the byte limit and the
+    /// throughput the track concurrency adapts to
+    async fn received(&self, bytes: u64) {
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.take(bytes).await;
+        }
+        self.quota.add_bytes(bytes);
+        self.tracks.on_bytes(bytes);
     }
 
-    async fn download_segment(&self, url: &str) -> Result<Bytes> {
-        let response = self
-            .http_client
-            .get(url)
-            .timeout(std::time::Duration::from_secs(30))
-            .send()
-            .await
-            .context("Failed to send request")?;
+    /// Whether a job's file exists already, decided without asking the API
+    ///
+    /// Tracks in the download archive count as existing. Otherwise the extension a track
+    /// gets depends on the stream TIDAL offers, so this only looks for the one the requested
+    /// quality usually results in. Tracks that ended up with another extension go through
+    /// the playback info as before.
+    fn is_downloaded(&self, job: &TrackJob) -> bool {
+        match &job.item {
+            JobItem::Track { track, quality, .. } => {
+                // the archive knows tracks by ID, wherever their files went since
+                if self
+                    .archive
+                    .get(track.id)
+                    .is_some_and(|entry| entry.satisfies(*quality))
+                {
+                    return true;
+                }
 
-        if !response.status().is_success() {
-            anyhow::bail!("HTTP {}", response.status());
+                let extension = self.expected_extension(*quality);
+                job.dir
+                    .join(format!("{}.{}", job.base_name, extension))
+                    .exists()
+            }
+            JobItem::Video(_) => video::video_exists(&job.dir, &job.base_name),
         }
+    }
 
-        response.bytes().await.context("Failed to read bytes")
+    /// The extension `get_file_extension` gives tracks of a quality when it is available
+    fn expected_extension(&self, quality: QualityArg) -> &'static str {
+        match quality {
+            QualityArg::Low | QualityArg::High => "m4a",
+            QualityArg::Lossless => "flac",
+            QualityArg::HiRes => match self.dash_container {
+                DashContainer::Flac => "flac",
+                DashContainer::M4a => "m4a",
+            },
+        }
     }
 
-    fn get_file_extension(&self, playback_info: &TrackPlaybackInfoPostPaywallResponse) -> &str {
+    fn get_file_extension(
+        &self,
+        playback_info: &TrackPlaybackInfoPostPaywallResponse,
+        segment_list: Option<&SegmentList>,
+    ) -> &str {
         // Determine file extension based on manifest type and MIME type
         match &playback_info.manifest_parsed {
-            Some(ManifestType::Dash(_)) => "flac", // HiRes uses fragmented MP4 (m4a)
+            // HiRes uses fragmented MP4, FLAC in it is remuxed unless the MP4 is kept
+            Some(ManifestType::Dash(_)) => {
+                let is_flac = segment_list
+                    .and_then(|list| list.codecs.as_deref())
+                    .is_some_and(|codecs| codecs.to_ascii_lowercase().contains("flac"));
+                if is_flac && self.dash_container == DashContainer::Flac {
+                    "flac"
+                } else {
+                    "m4a"
+                }
+            }
             Some(ManifestType::Json(json)) => {
                 // Standard qualities - check MIME type
                 if json.mime_type.contains("flac") {
@@ -699,3 +1605,195 @@ impl Downloader {
         }
     }
 }
+
+fn concurrency(adaptive: bool, name: &'static str, max: usize) -> Concurrency {
+    if adaptive {
+        Concurrency::adaptive(name, max)
+    } else {
+        Concurrency::fixed(name, max)
+    }
+}
+
+/// What the download archive records about a track besides its file
+struct ArchiveEntry {
+    id: u64,
+    quality: &'static str,
+    requested: &'static str,
+    codec: String,
+}
+
+/// Marks errors of tracks that were downloaded completely but failed verification
+#[derive(Debug)]
+struct VerificationFailed;
+
+impl std::fmt::Display for VerificationFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Verification failed")
+    }
+}
+
+/// How long to wait before downloading a track again, `None` if it's no use
+///
+/// A file that fails verification was likely damaged on the way, so it is downloaded
+/// again even though the failure itself looks permanent.
+fn track_retry_delay(error: &anyhow::Error, attempt: u32) -> Option<std::time::Duration> {
+    if error.downcast_ref::<VerificationFailed>().is_some() {
+        return Some(backoff(attempt));
+    }
+    retry_delay(error, attempt)
+}
+
+/// Checks a downloaded track file, including its length against the TIDAL track length
+async fn verify_track(path: &Path, expected_secs: u32) -> Result<()> {
+    let file = path.to_path_buf();
+    let secs = tokio::task::spawn_blocking(move || verify::verify_file(&file))
+        .await
+        .context("Verification task failed")??;
+
+    if expected_secs > 0 && (secs - expected_secs as f64).abs() > DURATION_TOLERANCE_SECS {
+        anyhow::bail!(
+            "File is {:.1} s long but the track is {} s long",
+            secs,
+            expected_secs
+        );
+    }
+    Ok(())
+}
+
+/// Reads the segment list from the MPD in a DASH playback info response
+fn dash_segments(playback_info: &TrackPlaybackInfoPostPaywallResponse) -> Result<SegmentList> {
+    let mpd = base64::engine::general_purpose::STANDARD
+        .decode(playback_info.manifest.trim())
+        .context("Failed to decode DASH manifest")?;
+    let mpd = String::from_utf8(mpd).context("DASH manifest is not valid UTF-8")?;
+    dash::parse_manifest(&mpd)
+}
+
+/// Whether the file is at least `len` bytes long and begins with `prefix`
+async fn starts_with(path: &Path, prefix: &[u8], len: u64) -> bool {
+    let Ok(mut file) = tokio::fs::File::open(path).await else {
+        return false;
+    };
+    if file.metadata().await.map_or(true, |m| m.len() < len) {
+        return false;
+    }
+
+    let mut head = vec![0; prefix.len()];
+    file.read_exact(&mut head).await.is_ok() && head == prefix
+}
+
+/// Values for track templates. `position` is the playlist index or album track number
+fn track_values(
+    track: &Track,
+    metadata: &TrackMetadata,
+    position: u32,
+    quality: QualityArg,
+) -> TemplateValues {
+    TemplateValues::from([
+        (
+            "album_artist",
+            metadata
+                .album_artist
+                .clone()
+                .unwrap_or_else(|| track.artist.name.clone()),
+        ),
+        ("artist", track.artist.name.clone()),
+        ("album", track.album.title.clone()),
+        ("year", year(metadata.date.as_deref())),
+        ("disc", track.volume_number.to_string()),
+        ("track", track.track_number.to_string()),
+        ("position", position.to_string()),
+        ("title", track.title.clone()),
+        ("version", track.version.clone().unwrap_or_default()),
+        ("explicit", explicit_flag(track.explicit)),
+        (
+            "quality",
+            received_quality(&track.audio_quality, quality).to_string(),
+        ),
+        ("isrc", metadata.isrc.clone().unwrap_or_default()),
+        ("tidal_id", track.id.to_string()),
+    ])
+}
+
+/// Values for track templates when a video takes a track's place
+fn video_values(video: &Video, position: u32) -> TemplateValues {
+    TemplateValues::from([
+        ("album_artist", video.artist.name.clone()),
+        ("artist", video.artist.name.clone()),
+        (
+            "album",
+            video
+                .album
+                .as_ref()
+                .map(|a| a.title.clone())
+                .unwrap_or_default(),
+        ),
+        ("year", year(video.release_date.as_deref())),
+        ("disc", video.volume_number.to_string()),
+        ("track", video.track_number.to_string()),
+        ("position", position.to_string()),
+        ("title", video.title.clone()),
+        ("explicit", explicit_flag(video.explicit)),
+        ("tidal_id", video.id.to_string()),
+    ])
+}
+
+fn album_values(album: &Album) -> TemplateValues {
+    TemplateValues::from([
+        ("album_artist", album.artist.name.clone()),
+        ("artist", album.artist.name.clone()),
+        ("album", album.title.clone()),
+        ("year", year(album.release_date.as_deref())),
+        ("version", album.version.clone().unwrap_or_default()),
+        ("explicit", explicit_flag(album.explicit)),
+        ("tidal_id", album.id.to_string()),
+    ])
+}
+
+/// Splits a rendered track path into its directory under `output_dir` and base file name
+fn split_track_path(output_dir: &Path, relative: &Path, id: u64) -> (PathBuf, String) {
+    match relative.file_name() {
+        Some(file_name) => (
+            output_dir.join(relative.parent().unwrap_or(Path::new(""))),
+            file_name.to_string_lossy().into_owned(),
+        ),
+        // every placeholder was empty, fall back to the TIDAL ID
+        None => (output_dir.to_path_buf(), id.to_string()),
+    }
+}
+
+fn year(date: Option<&str>) -> String {
+    date.map(|d| d.chars().take(4).collect())
+        .unwrap_or_default()
+}
+
+fn explicit_flag(explicit: bool) -> String {
+    if explicit { "E" } else { "" }.to_string()
+}
+
+/// Quality the track will be delivered in: the requested one, capped by what TIDAL offers
+fn received_quality(available: &str, requested: QualityArg) -> &'static str {
+    let available = match available {
+        "LOW" => QualityArg::Low,
+        "HIGH" => QualityArg::High,
+        "LOSSLESS" => QualityArg::Lossless,
+        _ => QualityArg::HiRes,
+    };
+
+    let rank = |q: QualityArg| q as u8;
+    let quality = if rank(available) < rank(requested) {
+        available
+    } else {
+        requested
+    };
+    quality_name(quality)
+}
+
+fn quality_name(quality: QualityArg) -> &'static str {
+    match quality {
+        QualityArg::Low => "Low",
+        QualityArg::High => "High",
+        QualityArg::Lossless => "Lossless",
+        QualityArg::HiRes => "HiRes",
+    }
+}
@@ -0,0 +1,295 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use super::limiter::{Failure, classify};
+
+/// How often an adaptive limit is reconsidered
+const WINDOW: Duration = Duration::from_secs(2);
+
+/// How much the throughput has to grow over the last window to count as still scaling
+const SCALING_GAIN: f64 = 1.05;
+
+/// A request slower than this many times the average counts as a latency spike
+const LATENCY_SPIKE: f64 = 3.0;
+
+/// Requests measured before latency spikes are detected
+const LATENCY_WARMUP: u32 = 5;
+
+/// Limits how many downloads run at once, optionally adapting the limit AIMD-style
+///
+/// An adaptive limit grows by one every window in which throughput still grew with all
+/// slots busy, and halves on 429s, server errors and latency spikes.
+pub struct Concurrency {
+    name: &'static str,
+    max: usize,
+    adaptive: bool,
+    state: Mutex<State>,
+    released: Notify,
+}
+
+struct State {
+    limit: usize,
+    in_flight: usize,
+    peak: usize,
+    window_start: Instant,
+    window_bytes: u64,
+    last_throughput: Option<f64>,
+    last_decrease: Option<Instant>,
+    /// Moving average of request latency in seconds
+    latency: f64,
+    latency_samples: u32,
+}
+
+/// A running download, which frees its slot when dropped
+pub struct Permit<'a> {
+    concurrency: &'a Concurrency,
+}
+
+impl Concurrency {
+    pub fn fixed(name: &'static str, limit: usize) -> Self {
+        Self::new(name, limit.max(1), limit.max(1), false)
+    }
+
+    /// Starts at half of `max` and adapts between 1 and `max`
+    pub fn adaptive(name: &'static str, max: usize) -> Self {
+        let max = max.max(1);
+        Self::new(name, max.div_ceil(2), max, true)
+    }
+
+    fn new(name: &'static str, limit: usize, max: usize, adaptive: bool) -> Self {
+        Self {
+            name,
+            max,
+            adaptive,
+            state: Mutex::new(State {
+                limit,
+                in_flight: 0,
+                peak: limit,
+                window_start: Instant::now(),
+                window_bytes: 0,
+                last_throughput: None,
+                last_decrease: None,
+                latency: 0.0,
+                latency_samples: 0,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    /// Highest limit this can reach
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Waits for a free slot
+    pub async fn acquire(&self) -> Permit<'_> {
+        loop {
+            // registered before checking, so a slot freed in between isn't missed
+            let released = self.released.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.limit {
+                    state.in_flight += 1;
+                    return Permit { concurrency: self };
+                }
+            }
+            released.await;
+        }
+    }
+
+    /// Records downloaded bytes for the throughput measurement
+    pub fn on_bytes(&self, bytes: u64) {
+        self.on_bytes_at(bytes, Instant::now());
+    }
+
+    fn on_bytes_at(&self, bytes: u64, now: Instant) {
+        if !self.adaptive {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.window_bytes += bytes;
+
+        let elapsed = now.duration_since(state.window_start);
+        if elapsed < WINDOW {
+            return;
+        }
+
+        let throughput = state.window_bytes as f64 / elapsed.as_secs_f64();
+        let scaling = state
+            .last_throughput
+            .is_none_or(|last| throughput > last * SCALING_GAIN);
+        let recently_decreased = state
+            .last_decrease
+            .is_some_and(|at| now.duration_since(at) < WINDOW);
+
+        // a limit that isn't used up says nothing about whether more would help
+        if scaling
+            && !recently_decreased
+            && state.in_flight >= state.limit
+            && state.limit < self.max
+        {
+            state.limit += 1;
+            state.peak = state.peak.max(state.limit);
+            self.released.notify_waiters();
+        }
+
+        state.last_throughput = Some(throughput);
+        state.window_start = now;
+        state.window_bytes = 0;
+    }
+
+    /// Records how long a request took, backing off on latency spikes
+    pub fn on_latency(&self, latency: Duration) {
+        self.on_latency_at(latency, Instant::now());
+    }
+
+    fn on_latency_at(&self, latency: Duration, now: Instant) {
+        if !self.adaptive {
+            return;
+        }
+        let secs = latency.as_secs_f64();
+        let spike = {
+            let mut state = self.state.lock().unwrap();
+            let spike =
+                state.latency_samples >= LATENCY_WARMUP && secs > state.latency * LATENCY_SPIKE;
+            state.latency = if state.latency_samples == 0 {
+                secs
+            } else {
+                state.latency * 0.8 + secs * 0.2
+            };
+            state.latency_samples += 1;
+            spike
+        };
+
+        if spike {
+            self.decrease_at(now);
+        }
+    }
+
+    /// Backs off if the error says the server is overloaded
+    pub fn on_error(&self, error: &anyhow::Error) {
+        if matches!(
+            classify(error),
+            Failure::RateLimited(_) | Failure::Transient
+        ) {
+            self.decrease_at(Instant::now());
+        }
+    }
+
+    /// Halves the limit, at most once per window so a burst of failures counts once
+    fn decrease_at(&self, now: Instant) {
+        if !self.adaptive {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state
+            .last_decrease
+            .is_some_and(|at| now.duration_since(at) < WINDOW)
+        {
+            return;
+        }
+
+        state.limit = (state.limit / 2).max(1);
+        state.last_decrease = Some(now);
+        // throughput at the old limit is no baseline for the new one
+        state.last_throughput = None;
+        state.window_start = now;
+        state.window_bytes = 0;
+    }
+
+    /// Current and highest limit, for the summary
+    pub fn describe(&self) -> String {
+        let state = self.state.lock().unwrap();
+        if self.adaptive {
+            format!(
+                "{}: {} at the end, up to {} (adaptive, max {})",
+                self.name, state.limit, state.peak, self.max
+            )
+        } else {
+            format!("{}: {}", self.name, state.limit)
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.concurrency.state.lock().unwrap().in_flight -= 1;
+        self.concurrency.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(concurrency: &Concurrency) -> usize {
+        concurrency.state.lock().unwrap().limit
+    }
+
+    #[tokio::test]
+    async fn test_additive_increase_while_scaling() {
+        let concurrency = Concurrency::adaptive("segments", 6);
+        assert_eq!(limit(&concurrency), 3);
+        let _permits = [
+            concurrency.acquire().await,
+            concurrency.acquire().await,
+            concurrency.acquire().await,
+        ];
+
+        let start = Instant::now();
+        concurrency.on_bytes_at(1_000_000, start + WINDOW);
+        assert_eq!(limit(&concurrency), 4);
+
+        // throughput didn't grow with the extra slot, so the limit stays
+        concurrency.on_bytes_at(1_000_000, start + WINDOW * 2);
+        assert_eq!(limit(&concurrency), 4);
+    }
+
+    #[tokio::test]
+    async fn test_idle_slots_do_not_increase() {
+        let concurrency = Concurrency::adaptive("tracks", 6);
+        let _permit = concurrency.acquire().await;
+        concurrency.on_bytes_at(1_000_000, Instant::now() + WINDOW);
+        assert_eq!(limit(&concurrency), 3);
+    }
+
+    #[test]
+    fn test_multiplicative_decrease() {
+        let concurrency = Concurrency::adaptive("segments", 16);
+        let now = Instant::now();
+        concurrency.decrease_at(now);
+        assert_eq!(limit(&concurrency), 4);
+
+        // a burst of failures only halves once per window
+        concurrency.decrease_at(now + WINDOW / 2);
+        assert_eq!(limit(&concurrency), 4);
+
+        concurrency.decrease_at(now + WINDOW);
+        concurrency.decrease_at(now + WINDOW * 2);
+        concurrency.decrease_at(now + WINDOW * 3);
+        assert_eq!(limit(&concurrency), 1);
+    }
+
+    #[test]
+    fn test_latency_spike() {
+        let concurrency = Concurrency::adaptive("segments", 8);
+        let now = Instant::now();
+        for _ in 0..LATENCY_WARMUP {
+            concurrency.on_latency_at(Duration::from_millis(200), now);
+        }
+        concurrency.on_latency_at(Duration::from_millis(400), now);
+        assert_eq!(limit(&concurrency), 4);
+
+        concurrency.on_latency_at(Duration::from_secs(2), now);
+        assert_eq!(limit(&concurrency), 2);
+    }
+
+    #[test]
+    fn test_fixed_limit() {
+        let concurrency = Concurrency::fixed("tracks", 5);
+        concurrency.decrease_at(Instant::now());
+        assert_eq!(limit(&concurrency), 5);
+        assert_eq!(concurrency.describe(), "tracks: 5");
+    }
+}
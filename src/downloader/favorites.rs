@@ -0,0 +1,124 @@
+use anyhow::{Context, Result};
+use tidlers::client::TidalClient;
+
+use super::Downloader;
+use crate::types::{Favorites, ReleaseFilter};
+
+impl Downloader {
+    /// Downloads a part of the logged-in user's collection
+    ///
+    /// Every favorite is planned like a standalone download of its kind, then all tracks
+    /// go through one shared worker pool.
+    pub async fn download_favorites(
+        &self,
+        client: &mut TidalClient,
+        favorites: Favorites,
+        filter: &ReleaseFilter,
+    ) -> Result<()> {
+        if client.user_info.is_none() {
+            client
+                .refresh_user_info()
+                .await
+                .context("Failed to get user info")?;
+        }
+        let user_id = client
+            .user_info
+            .as_ref()
+            .map(|user| user.user_id.to_string())
+            .context("No user info in session")?;
+
+        let mut jobs = Vec::new();
+        let mut offset = 0;
+        let limit = 100;
+
+        match favorites {
+            Favorites::Tracks => loop {
+                let page = client
+                    .get_user_favorite_tracks(user_id.clone(), Some(limit), Some(offset))
+                    .await
+                    .context("Failed to get favorite tracks")?;
+
+                let fetched = page.items.len();
+                for favorite in page.items {
+                    jobs.push(self.single_track_job(favorite.item, self.quality));
+                }
+
+                offset += limit;
+                if fetched == 0 || offset >= page.total_number_of_items {
+                    break;
+                }
+            },
+            Favorites::Albums => loop {
+                let page = client
+                    .get_user_favorite_albums(user_id.clone(), Some(limit), Some(offset))
+                    .await
+                    .context("Failed to get favorite albums")?;
+
+                let fetched = page.items.len();
+                for favorite in page.items {
+                    let album = favorite.item;
+                    println!("  {} - {}", album.artist.name, album.title);
+                    match self.prepare_album(client, &album, self.quality).await {
+                        Ok(album_jobs) => jobs.extend(album_jobs),
+                        Err(e) => println!("    skipping album: {:#}", e),
+                    }
+                }
+
+                offset += limit;
+                if fetched == 0 || offset >= page.total_number_of_items {
+                    break;
+                }
+            },
+            Favorites::Playlists => loop {
+                let page = client
+                    .get_user_favorite_playlists(user_id.clone(), Some(limit), Some(offset))
+                    .await
+                    .context("Failed to get favorite playlists")?;
+
+                let fetched = page.items.len();
+                for favorite in page.items {
+                    let playlist = favorite.item;
+                    println!("  {}", playlist.title);
+                    match self
+                        .prepare_playlist(client, &playlist.uuid, self.quality)
+                        .await
+                    {
+                        Ok(playlist_jobs) => jobs.extend(playlist_jobs),
+                        Err(e) => println!("    skipping playlist: {:#}", e),
+                    }
+                }
+
+                offset += limit;
+                if fetched == 0 || offset >= page.total_number_of_items {
+                    break;
+                }
+            },
+            Favorites::Artists => loop {
+                let page = client
+                    .get_user_favorite_artists(user_id.clone(), Some(limit), Some(offset))
+                    .await
+                    .context("Failed to get favorite artists")?;
+
+                let fetched = page.items.len();
+                for favorite in page.items {
+                    let artist = favorite.item;
+                    match self
+                        .prepare_artist(client, &artist.id.to_string(), filter, self.quality)
+                        .await
+                    {
+                        Ok(artist_jobs) => jobs.extend(artist_jobs),
+                        Err(e) => println!("    skipping artist {}: {:#}", artist.name, e),
+                    }
+                }
+
+                offset += limit;
+                if fetched == 0 || offset >= page.total_number_of_items {
+                    break;
+                }
+            },
+        }
+
+        self.download_tracks_parallel(client, jobs).await.print();
+        Ok(())
+    }
+}
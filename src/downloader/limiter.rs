@@ -0,0 +1,431 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use rand::Rng;
+use reqwest::StatusCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+use super::timeouts::TransferTimeout;
+
+/// Upper bound of the first retry delay, doubled with every further attempt
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+
+/// Longest delay between two attempts when the server doesn't say how long to wait
+const BACKOFF_CAP: Duration = Duration::from_secs(60);
+
+/// Longest pause a `Retry-After` header can cause
+const RETRY_AFTER_CAP: Duration = Duration::from_secs(300);
+
+/// An unsuccessful HTTP response, keeping what the server said about retrying
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: StatusCode,
+    pub retry_after: Option<Duration>,
+}
+
+impl HttpError {
+    pub fn from_response(response: &reqwest::Response) -> Self {
+        Self {
+            status: response.status(),
+            retry_after: response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after),
+        }
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP {}", self.status)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// What a failed request means for retrying it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    /// 429 Too Many Requests, with the delay the server asked for
+    RateLimited(Option<Duration>),
+    /// Server errors, timeouts, stalled transfers and dropped connections
+    Transient,
+    /// 401 and 403, the access token or the signed stream URLs expired
+    Unauthorized,
+    /// Other client errors, disk errors and broken data, which waiting doesn't fix
+    Permanent,
+}
+
+pub fn classify(error: &anyhow::Error) -> Failure {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<HttpError>() {
+            return classify_status(e.status, e.retry_after);
+        }
+        if cause.is::<TransferTimeout>() {
+            return Failure::Transient;
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            if let Some(status) = e.status() {
+                return classify_status(status, None);
+            }
+            if e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() {
+                return Failure::Transient;
+            }
+        }
+    }
+
+    // the TIDAL client only reports the status of failed API calls in its message
+    match status_in_message(&format!("{:#}", error)) {
+        Some(
+            status @ (StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::UNAUTHORIZED
+            | StatusCode::FORBIDDEN),
+        ) => classify_status(status, None),
+        _ => Failure::Permanent,
+    }
+}
+
+/// Finds a status code in an error message, either after "status" or "HTTP", or followed by
+/// its reason phrase as in "429 Too Many Requests"
+///
+/// Other numbers are ignored, since messages also carry byte counts, sample counts and
+/// paths.
+fn status_in_message(message: &str) -> Option<StatusCode> {
+    let lower = message.to_ascii_lowercase();
+    for (start, _) in lower.match_indices(|c: char| c.is_ascii_digit()) {
+        let code = &lower[start..];
+        let end = code
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(code.len());
+        if end != 3 || lower[..start].ends_with(|c: char| c.is_ascii_digit()) {
+            continue;
+        }
+        let Ok(status) = StatusCode::from_bytes(&code.as_bytes()[..3]) else {
+            continue;
+        };
+
+        let before = lower[..start].trim_end_matches([' ', ':', '=']);
+        let after_status = ["status", "status code", "http"]
+            .iter()
+            .any(|marker| before.ends_with(marker));
+        let with_reason = status.canonical_reason().is_some_and(|reason| {
+            code[3..]
+                .trim_start()
+                .starts_with(&reason.to_ascii_lowercase())
+        });
+        if after_status || with_reason {
+            return Some(status);
+        }
+    }
+    None
+}
+
+fn classify_status(status: StatusCode, retry_after: Option<Duration>) -> Failure {
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        Failure::RateLimited(retry_after)
+    } else if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        Failure::Unauthorized
+    } else if status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT {
+        Failure::Transient
+    } else {
+        Failure::Permanent
+    }
+}
+
+/// Parses a `Retry-After` value, either seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Exponential backoff with jitter: between half and all of `BACKOFF_BASE * 2^(attempt - 1)`,
+/// capped at `BACKOFF_CAP`
+pub fn backoff(attempt: u32) -> Duration {
+    let max = BACKOFF_BASE
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(BACKOFF_CAP);
+    max.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+/// How long to wait before the next attempt after a failed one, `None` if another
+/// attempt can't succeed
+///
+/// Rate limiting is waited out through the service's `RateLimiter` instead, so it adds
+/// no delay of its own here.
+pub fn retry_delay(error: &anyhow::Error, attempt: u32) -> Option<Duration> {
+    match classify(error) {
+        Failure::Transient => Some(backoff(attempt)),
+        Failure::RateLimited(_) | Failure::Unauthorized => Some(Duration::ZERO),
+        Failure::Permanent => None,
+    }
+}
+
+/// Pauses every request to one service while it is rate limiting us
+///
+/// The TIDAL API and the CDN have separate limiters, so a throttled API doesn't stop
+/// segment downloads that are already running, and the other way around.
+pub struct RateLimiter {
+    name: &'static str,
+    multi_progress: MultiProgress,
+    state: Mutex<PauseState>,
+}
+
+#[derive(Default)]
+struct PauseState {
+    paused_until: Option<Instant>,
+    /// Pauses in a row without a successful request in between
+    strikes: u32,
+    /// Whether the pause is currently shown on the progress display
+    shown: bool,
+}
+
+impl RateLimiter {
+    pub fn new(name: &'static str, multi_progress: MultiProgress) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            multi_progress,
+            state: Mutex::new(PauseState::default()),
+        })
+    }
+
+    /// Waits until a pause of this service is over
+    pub async fn wait(&self) {
+        loop {
+            let paused_until = self.state.lock().unwrap().paused_until;
+            match paused_until {
+                Some(until) if until > Instant::now() => tokio::time::sleep_until(until).await,
+                _ => return,
+            }
+        }
+    }
+
+    pub fn on_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .paused_until
+            .is_none_or(|until| until <= Instant::now())
+        {
+            state.strikes = 0;
+        }
+    }
+
+    /// Pauses the service if the error is a rate limit response
+    pub fn on_error(self: &Arc<Self>, error: &anyhow::Error) {
+        let Failure::RateLimited(retry_after) = classify(error) else {
+            return;
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        // requests that were already in flight report the same rate limit again
+        let paused = state.paused_until.is_some_and(|until| until > now);
+        if !paused {
+            state.strikes += 1;
+        }
+
+        let delay = match retry_after {
+            Some(retry_after) => retry_after.min(RETRY_AFTER_CAP),
+            None => backoff(state.strikes),
+        };
+        let until = now + delay;
+        if state.paused_until.is_none_or(|current| current < until) {
+            state.paused_until = Some(until);
+        }
+
+        if !state.shown {
+            state.shown = true;
+            self.show_pause();
+        }
+    }
+
+    /// Shows a countdown line on the progress display until the pause is over
+    fn show_pause(self: &Arc<Self>) {
+        let pb = self.multi_progress.add(ProgressBar::new_spinner());
+        pb.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner:.yellow} {msg}")
+                .unwrap(),
+        );
+        pb.enable_steady_tick(Duration::from_millis(250));
+
+        let limiter = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                let remaining = {
+                    let mut state = limiter.state.lock().unwrap();
+                    let remaining = state.paused_until.map_or(Duration::ZERO, |until| {
+                        until.saturating_duration_since(Instant::now())
+                    });
+                    if remaining.is_zero() {
+                        state.shown = false;
+                    }
+                    remaining
+                };
+                if remaining.is_zero() {
+                    break;
+                }
+
+                pb.set_message(format!(
+                    "{} is rate limiting, pausing its requests for {} s...",
+                    limiter.name,
+                    remaining.as_secs_f64().ceil()
+                ));
+                tokio::time::sleep(remaining.min(Duration::from_secs(1))).await;
+            }
+            pb.finish_and_clear();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn test_classify() {
+        let error = |status, retry_after| {
+            anyhow::Error::new(HttpError {
+                status,
+                retry_after,
+            })
+            .context("Failed to download segment 3/40")
+        };
+
+        assert_eq!(
+            classify(&error(
+                StatusCode::TOO_MANY_REQUESTS,
+                Some(Duration::from_secs(7))
+            )),
+            Failure::RateLimited(Some(Duration::from_secs(7)))
+        );
+        assert_eq!(
+            classify(&error(StatusCode::BAD_GATEWAY, None)),
+            Failure::Transient
+        );
+        assert_eq!(
+            classify(&error(StatusCode::NOT_FOUND, None)),
+            Failure::Permanent
+        );
+        assert_eq!(
+            classify(&error(StatusCode::FORBIDDEN, None)),
+            Failure::Unauthorized
+        );
+        assert_eq!(
+            classify(&anyhow::anyhow!("Failed to write file")),
+            Failure::Permanent
+        );
+    }
+
+    #[test]
+    fn test_classify_message() {
+        assert_eq!(
+            classify(&anyhow::anyhow!("Request failed with status 429")),
+            Failure::RateLimited(None)
+        );
+        assert_eq!(
+            classify(&anyhow::anyhow!("API error: 429 Too Many Requests")),
+            Failure::RateLimited(None)
+        );
+
+        assert_eq!(
+            classify(&anyhow::anyhow!("Failed to get playback info: HTTP 401")),
+            Failure::Unauthorized
+        );
+        assert_eq!(
+            classify(&anyhow::anyhow!("status code: 403 Forbidden")),
+            Failure::Unauthorized
+        );
+
+        // numbers that merely contain a status code
+        assert_eq!(
+            classify(&anyhow::anyhow!(
+                "Download incomplete: 4290123 of 5000000 bytes"
+            )),
+            Failure::Permanent
+        );
+        assert_eq!(
+            classify(&anyhow::anyhow!(
+                "FLAC stream has 4290000 of 4300000 samples"
+            )),
+            Failure::Permanent
+        );
+        assert_eq!(
+            classify(&anyhow::anyhow!("Failed to write yadal/429 - Title.flac")),
+            Failure::Permanent
+        );
+        assert_eq!(
+            classify(
+                &anyhow::anyhow!("FLAC stream has 4030000 of 4031000 samples")
+                    .context("Verification failed")
+            ),
+            Failure::Permanent
+        );
+        assert_eq!(
+            classify(&anyhow::anyhow!("Download incomplete: 401 of 5000 bytes")),
+            Failure::Permanent
+        );
+        assert_eq!(
+            classify(&anyhow::anyhow!(
+                "Failed to write yadal/Forbidden - 403.flac: Unauthorized access"
+            )),
+            Failure::Permanent
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let http = |status| {
+            anyhow::Error::new(HttpError {
+                status,
+                retry_after: None,
+            })
+        };
+        let delay = retry_delay(&http(StatusCode::BAD_GATEWAY), 3).unwrap();
+        assert!(delay >= BACKOFF_BASE * 2);
+        assert_eq!(
+            retry_delay(&http(StatusCode::TOO_MANY_REQUESTS), 3),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            retry_delay(&http(StatusCode::FORBIDDEN), 3),
+            Some(Duration::ZERO)
+        );
+
+        // waiting doesn't bring back a missing file or fix a full disk
+        assert_eq!(retry_delay(&http(StatusCode::NOT_FOUND), 1), None);
+        assert_eq!(
+            retry_delay(&anyhow::anyhow!("Failed to write file"), 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        for attempt in 1..20 {
+            let delay = backoff(attempt);
+            let max = BACKOFF_BASE * 2u32.pow(attempt.min(16) - 1);
+            assert!(delay <= max.min(BACKOFF_CAP));
+            assert!(delay >= max.min(BACKOFF_CAP) / 2);
+        }
+    }
+}
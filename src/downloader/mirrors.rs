@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::limiter::{Failure, HttpError, classify};
+
+/// How long failures of a host count against it
+const FAILURE_MEMORY: Duration = Duration::from_secs(600);
+
+/// Remembers which CDN hosts of the manifest URLs worked during the run
+///
+/// Manifests often offer several mirrors of a track. Hosts that failed recently are
+/// tried last, the order of the manifest decides otherwise.
+#[derive(Default)]
+pub struct Mirrors {
+    hosts: Mutex<HashMap<String, HostHealth>>,
+}
+
+#[derive(Default)]
+struct HostHealth {
+    successes: u32,
+    failures: u32,
+    /// Failures since the last success
+    failures_in_row: u32,
+    last_failure: Option<Instant>,
+}
+
+impl HostHealth {
+    fn penalty(&self, now: Instant) -> u32 {
+        match self.last_failure {
+            Some(at) if now.duration_since(at) < FAILURE_MEMORY => self.failures_in_row,
+            _ => 0,
+        }
+    }
+}
+
+impl Mirrors {
+    /// The URLs in the order they should be tried
+    pub fn order<'a>(&self, urls: &'a [String]) -> Vec<&'a String> {
+        self.order_at(urls, Instant::now())
+    }
+
+    fn order_at<'a>(&self, urls: &'a [String], now: Instant) -> Vec<&'a String> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut ordered: Vec<&String> = urls.iter().collect();
+        // stable, so equally healthy mirrors keep the manifest order
+        ordered.sort_by_key(|url| hosts.get(&host(url)).map_or(0, |h| h.penalty(now)));
+        ordered
+    }
+
+    pub fn on_success(&self, url: &str) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let health = hosts.entry(host(url)).or_default();
+        health.successes += 1;
+        health.failures_in_row = 0;
+    }
+
+    pub fn on_failure(&self, url: &str) {
+        self.on_failure_at(url, Instant::now());
+    }
+
+    fn on_failure_at(&self, url: &str, now: Instant) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let health = hosts.entry(host(url)).or_default();
+        health.failures += 1;
+        health.failures_in_row += 1;
+        health.last_failure = Some(now);
+    }
+
+    /// Successes and failures per host, for the summary; empty if no mirror ever failed
+    pub fn describe(&self) -> Vec<String> {
+        let hosts = self.hosts.lock().unwrap();
+        if hosts.values().all(|h| h.failures == 0) {
+            return Vec::new();
+        }
+
+        let mut lines: Vec<String> = hosts
+            .iter()
+            .map(|(host, h)| format!("{}: {} ok, {} failed", host, h.successes, h.failures))
+            .collect();
+        lines.sort();
+        lines
+    }
+}
+
+/// Host name of a URL, the unit mirrors are tracked by
+pub fn host(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| url.to_string())
+}
+
+/// Whether another mirror could do better, as opposed to errors such as a full disk, a
+/// rate limit of the whole CDN or expired URLs
+pub fn is_mirror_failure(error: &anyhow::Error) -> bool {
+    match classify(error) {
+        Failure::Transient => true,
+        Failure::RateLimited(_) | Failure::Unauthorized => false,
+        // a host that doesn't have the file
+        Failure::Permanent => error
+            .chain()
+            .any(|cause| cause.is::<HttpError>() || cause.is::<reqwest::Error>()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::downloader::timeouts::TransferTimeout;
+    use reqwest::StatusCode;
+
+    fn urls() -> Vec<String> {
+        vec![
+            "https://a.example.com/track.flac?token=1".to_string(),
+            "https://b.example.com/track.flac?token=1".to_string(),
+            "https://c.example.com/track.flac?token=1".to_string(),
+        ]
+    }
+
+    #[test]
+    fn test_failed_hosts_are_tried_last() {
+        let mirrors = Mirrors::default();
+        let urls = urls();
+        let now = Instant::now();
+        assert_eq!(mirrors.order_at(&urls, now), [&urls[0], &urls[1], &urls[2]]);
+
+        // failures count per host, not per signed URL
+        mirrors.on_failure_at("https://a.example.com/other.flac", now);
+        mirrors.on_failure_at(&urls[1], now);
+        mirrors.on_failure_at(&urls[1], now);
+        assert_eq!(mirrors.order_at(&urls, now), [&urls[2], &urls[0], &urls[1]]);
+
+        // a success clears the penalty, old failures are forgiven
+        mirrors.on_success(&urls[0]);
+        assert_eq!(mirrors.order_at(&urls, now), [&urls[0], &urls[2], &urls[1]]);
+        assert_eq!(
+            mirrors.order_at(&urls, now + FAILURE_MEMORY),
+            [&urls[0], &urls[1], &urls[2]]
+        );
+
+        assert_eq!(
+            mirrors.describe(),
+            [
+                "a.example.com: 1 ok, 1 failed",
+                "b.example.com: 0 ok, 2 failed"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_mirror_failure() {
+        let http = |status| {
+            anyhow::Error::new(HttpError {
+                status,
+                retry_after: None,
+            })
+        };
+        assert!(is_mirror_failure(&http(StatusCode::NOT_FOUND)));
+        assert!(is_mirror_failure(&http(StatusCode::BAD_GATEWAY)));
+        assert!(!is_mirror_failure(&http(StatusCode::TOO_MANY_REQUESTS)));
+        assert!(!is_mirror_failure(&http(StatusCode::FORBIDDEN)));
+        assert!(is_mirror_failure(
+            &anyhow::Error::new(TransferTimeout::Stalled(Duration::from_secs(30)))
+                .context("Failed to download")
+        ));
+        assert!(!is_mirror_failure(&anyhow::anyhow!("Failed to write file")));
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+
+use super::Downloader;
+
+/// Extensions of the files yadal downloads, whose unfinished copies are cleaned up
+const MEDIA_EXTENSIONS: [&str; 4] = ["flac", "m4a", "mp4", "ts"];
+
+/// How far a `.part` file got, stored next to it so an interrupted download can be resumed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum ResumeState {
+    /// A single file download, resumed with a Range request for the same CDN object
+    File {
+        etag: Option<String>,
+        last_modified: Option<String>,
+        total_size: Option<u64>,
+    },
+    /// A DASH download, resumed after the last completely written media segment
+    Segments {
+        /// Number of media segments written after the init segment
+        segments: u32,
+        /// Length of the init segment and all written media segments
+        bytes: u64,
+    },
+}
+
+impl ResumeState {
+    pub(super) fn load(part_path: &Path) -> Option<Self> {
+        let data = std::fs::read(resume_path(part_path)).ok()?;
+        let value: serde_json::Value = serde_json::from_slice(&data).ok()?;
+        let text = |key: &str| value[key].as_str().map(str::to_string);
+
+        match value["kind"].as_str()? {
+            "file" => Some(ResumeState::File {
+                etag: text("etag"),
+                last_modified: text("last_modified"),
+                total_size: value["total_size"].as_u64(),
+            }),
+            "segments" => Some(ResumeState::Segments {
+                segments: u32::try_from(value["segments"].as_u64()?).ok()?,
+                bytes: value["bytes"].as_u64()?,
+            }),
+            _ => None,
+        }
+    }
+
+    pub(super) fn save(&self, part_path: &Path) -> Result<()> {
+        let value = match self {
+            ResumeState::File {
+                etag,
+                last_modified,
+                total_size,
+            } => json!({
+                "kind": "file",
+                "etag": etag,
+                "last_modified": last_modified,
+                "total_size": total_size,
+            }),
+            ResumeState::Segments { segments, bytes } => json!({
+                "kind": "segments",
+                "segments": segments,
+                "bytes": bytes,
+            }),
+        };
+        std::fs::write(resume_path(part_path), value.to_string())
+            .context("Failed to save download progress")
+    }
+
+    pub(super) fn remove(part_path: &Path) {
+        let _ = std::fs::remove_file(resume_path(part_path));
+    }
+}
+
+impl Downloader {
+    /// Removes files left behind by interrupted runs from the output directory
+    ///
+    /// `.part` files with saved progress are kept so their downloads can be resumed.
+    /// Returns how many files were removed.
+    pub fn remove_stale_parts(&self) -> Result<usize> {
+        remove_unfinished(&self.output_dir)
+    }
+}
+
+fn remove_unfinished(dir: &Path) -> Result<usize> {
+    let mut removed = 0;
+
+    for entry in std::fs::read_dir(dir).context("Failed to read output directory")? {
+        let path = entry.context("Failed to read output directory")?.path();
+
+        if path.is_dir() {
+            removed += remove_unfinished(&path)?;
+            continue;
+        }
+
+        if is_stale(&path) {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Whether a file is a leftover of a yadal download that can't be finished
+///
+/// Only names yadal writes itself are matched, so unfinished downloads of other programs
+/// in the output directory are left alone.
+fn is_stale(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let is_part = |name: &str| {
+        name.strip_suffix(".part")
+            .and_then(|media| Path::new(media).extension())
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| MEDIA_EXTENSIONS.contains(&ext))
+    };
+
+    if let Some(part) = name
+        .strip_suffix(".tagging")
+        .or_else(|| name.strip_suffix(".remux"))
+    {
+        // a tagged or remuxed copy that was never swapped in
+        is_part(part)
+    } else if let Some(part) = name.strip_suffix(".resume") {
+        // progress of a download that no longer exists
+        is_part(part) && !path.with_extension("").exists()
+    } else {
+        // a download that can't be resumed
+        is_part(name) && !resume_path(path).exists()
+    }
+}
+
+/// Parses a `Content-Range: bytes <start>-<end>/<total>` header into its start and total
+pub(super) fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, total.trim().parse().ok()))
+}
+
+fn resume_path(part_path: &Path) -> PathBuf {
+    let mut file_name = part_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".resume");
+    part_path.with_file_name(file_name)
+}
+
+/// Path a download is written to before it is complete
+pub(super) fn part_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".part");
+    path.with_file_name(file_name)
+}
+
+/// Flushes a finished download to disk and moves it to its final path
+pub(super) fn finish_part(part_path: &Path, path: &Path) -> Result<()> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(part_path)
+        .and_then(|file| file.sync_all())
+        .context("Failed to sync file")?;
+    std::fs::rename(part_path, path).context("Failed to move file into place")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_part_path() {
+        assert_eq!(
+            part_path(Path::new("Album/01 - Intro.flac")),
+            Path::new("Album/01 - Intro.flac.part")
+        );
+    }
+
+    #[test]
+    fn test_remove_unfinished() {
+        let dir = std::env::temp_dir().join(format!("yadal-parts-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("CD1")).unwrap();
+        std::fs::write(dir.join("01 - Intro.flac"), b"done").unwrap();
+        std::fs::write(dir.join("02 - Song.flac.part"), b"partial").unwrap();
+        std::fs::write(dir.join("03 - Outro.flac.part"), b"partial").unwrap();
+        std::fs::write(dir.join("03 - Outro.flac.part.resume"), b"{}").unwrap();
+        std::fs::write(dir.join("04 - Gone.flac.part.resume"), b"{}").unwrap();
+        std::fs::write(dir.join("CD1/01 - Intro.m4a.part.tagging"), b"partial").unwrap();
+        // unfinished files of other programs
+        std::fs::write(dir.join("foo.zip.part"), b"partial").unwrap();
+        std::fs::write(dir.join("notes.txt.tagging"), b"partial").unwrap();
+        std::fs::write(dir.join("state.resume"), b"{}").unwrap();
+
+        assert_eq!(remove_unfinished(&dir).unwrap(), 3);
+        assert!(dir.join("foo.zip.part").exists());
+        assert!(dir.join("notes.txt.tagging").exists());
+        assert!(dir.join("state.resume").exists());
+        assert!(dir.join("01 - Intro.flac").exists());
+        assert!(!dir.join("02 - Song.flac.part").exists());
+        assert!(dir.join("03 - Outro.flac.part").exists());
+        assert!(!dir.join("04 - Gone.flac.part.resume").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resume_state_round_trip() {
+        let dir = std::env::temp_dir().join(format!("yadal-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let part = dir.join("01 - Intro.flac.part");
+
+        let states = [
+            ResumeState::File {
+                etag: Some("\"abc\"".to_string()),
+                last_modified: None,
+                total_size: Some(1000),
+            },
+            ResumeState::Segments {
+                segments: 12,
+                bytes: 4096,
+            },
+        ];
+        for state in states {
+            state.save(&part).unwrap();
+            assert_eq!(ResumeState::load(&part), Some(state));
+        }
+
+        ResumeState::remove(&part);
+        assert_eq!(ResumeState::load(&part), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 100-999/1000"),
+            Some((100, Some(1000)))
+        );
+        assert_eq!(parse_content_range("bytes 100-999/*"), Some((100, None)));
+        assert_eq!(parse_content_range("items 0-1/2"), None);
+    }
+}
@@ -0,0 +1,191 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Caps a rate, such as the combined download rate of all tracks and segments
+///
+/// A token bucket holding up to one second worth of tokens. Callers pay for what they
+/// use, like every chunk they receive, and wait while the bucket is in debt, in the order
+/// they arrived.
+pub struct TokenBucket {
+    per_sec: f64,
+    bucket: tokio::sync::Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_sec: u64) -> Self {
+        Self {
+            per_sec: per_sec as f64,
+            bucket: tokio::sync::Mutex::new(Bucket {
+                tokens: per_sec as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Pays `amount` tokens, waiting until the rate allows them
+    pub async fn take(&self, amount: u64) {
+        // holding the lock while waiting makes later chunks queue up behind this one
+        let mut bucket = self.bucket.lock().await;
+
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.refilled).as_secs_f64() * self.per_sec;
+        bucket.tokens = (bucket.tokens + refill).min(self.per_sec);
+        bucket.refilled = now;
+
+        bucket.tokens -= amount as f64;
+        if bucket.tokens < 0.0 {
+            let debt = Duration::from_secs_f64(-bucket.tokens / self.per_sec);
+            tokio::time::sleep(debt).await;
+            bucket.tokens = 0.0;
+            bucket.refilled = Instant::now();
+        }
+    }
+}
+
+/// Marks downloads that were stopped or never started because a limit of the run was reached
+#[derive(Debug)]
+pub struct QuotaReached;
+
+impl std::fmt::Display for QuotaReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Download limit of this run reached")
+    }
+}
+
+impl std::error::Error for QuotaReached {}
+
+/// Limits how many tracks and bytes one run downloads
+///
+/// Unfinished downloads keep their `.part` files, so the next run picks up where this one
+/// stopped.
+pub struct Quota {
+    max_downloads: Option<u64>,
+    max_bytes: Option<u64>,
+    /// Downloads that were started and haven't failed
+    downloads: Mutex<u64>,
+    bytes: AtomicU64,
+}
+
+impl Quota {
+    pub fn new(max_downloads: Option<u64>, max_bytes: Option<u64>) -> Self {
+        Self {
+            max_downloads,
+            max_bytes,
+            downloads: Mutex::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether no further download may start
+    pub fn is_reached(&self) -> bool {
+        let downloads_reached = self
+            .max_downloads
+            .is_some_and(|max| *self.downloads.lock().unwrap() >= max);
+        downloads_reached || self.check_bytes().is_err()
+    }
+
+    /// Claims one of the downloads of the run, given back if the claim is dropped before
+    /// the download completes
+    pub fn start_download(&self) -> Result<DownloadClaim<'_>, QuotaReached> {
+        self.check_bytes()?;
+
+        let mut downloads = self.downloads.lock().unwrap();
+        if self.max_downloads.is_some_and(|max| *downloads >= max) {
+            return Err(QuotaReached);
+        }
+        *downloads += 1;
+        Ok(DownloadClaim {
+            quota: self,
+            completed: false,
+        })
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Fails once the byte limit is used up, which also stops downloads that are running
+    pub fn check_bytes(&self) -> Result<(), QuotaReached> {
+        match self.max_bytes {
+            Some(max) if self.bytes.load(Ordering::SeqCst) >= max => Err(QuotaReached),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A download counted against the run's limit while it is in progress
+pub struct DownloadClaim<'a> {
+    quota: &'a Quota,
+    completed: bool,
+}
+
+impl DownloadClaim<'_> {
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for DownloadClaim<'_> {
+    fn drop(&mut self) {
+        // a failed download frees its place for another track
+        if !self.completed {
+            let mut downloads = self.quota.downloads.lock().unwrap();
+            *downloads = downloads.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_download_quota() {
+        let quota = Quota::new(Some(2), None);
+        quota.start_download().unwrap().complete();
+        let claim = quota.start_download().unwrap();
+        assert!(quota.start_download().is_err());
+        assert!(quota.is_reached());
+
+        // a failed download frees its place for another track
+        drop(claim);
+        assert!(!quota.is_reached());
+        quota.start_download().unwrap().complete();
+        assert!(quota.is_reached());
+    }
+
+    #[test]
+    fn test_byte_quota() {
+        let quota = Quota::new(None, Some(1000));
+        let _claim = quota.start_download().unwrap();
+        quota.add_bytes(600);
+        assert!(quota.check_bytes().is_ok());
+        quota.add_bytes(600);
+        assert!(quota.check_bytes().is_err());
+        assert!(quota.is_reached());
+        assert!(quota.start_download().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_token_bucket() {
+        let bucket = TokenBucket::new(10_000);
+        let start = Instant::now();
+
+        // the first second of traffic is covered by the full bucket
+        bucket.take(10_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // then each 1000 bytes take a tenth of a second
+        bucket.take(1000).await;
+        bucket.take(1000).await;
+        assert!(start.elapsed() >= Duration::from_millis(190));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use indicatif::HumanBytes;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When a media download is given up as hanging
+///
+/// There is no limit on how long a whole download takes, so large files finish on slow
+/// links as long as data keeps coming.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Longest wait for a connection to the server
+    pub connect: Duration,
+    /// Longest wait for the response headers and for each chunk of the body
+    pub stall: Duration,
+    /// Slowest accepted transfer rate in bytes per second, averaged over `stall`
+    pub min_speed: Option<u64>,
+}
+
+/// A media request that stopped making progress
+#[derive(Debug)]
+pub enum TransferTimeout {
+    NoResponse(Duration),
+    Stalled(Duration),
+    TooSlow { speed: u64, min_speed: u64 },
+}
+
+impl std::fmt::Display for TransferTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoResponse(after) => write!(f, "No response within {} s", after.as_secs()),
+            Self::Stalled(after) => write!(f, "No data received for {} s", after.as_secs()),
+            Self::TooSlow { speed, min_speed } => write!(
+                f,
+                "Transfer too slow: {}/s, below the minimum of {}/s",
+                HumanBytes(*speed),
+                HumanBytes(*min_speed)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransferTimeout {}
+
+impl Timeouts {
+    /// Sends a request, giving up if the response headers don't arrive within the stall timeout
+    pub async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        match tokio::time::timeout(self.stall, request.send()).await {
+            Ok(response) => response.context("Failed to send request"),
+            Err(_) => Err(TransferTimeout::NoResponse(self.stall).into()),
+        }
+    }
+
+    /// Watches the body of one response
+    pub fn body(&self) -> BodyWatch {
+        BodyWatch {
+            timeouts: *self,
+            waited: Duration::ZERO,
+            bytes: 0,
+        }
+    }
+}
+
+/// Reads a response body chunk by chunk, failing on stalls and too slow transfers
+pub struct BodyWatch {
+    timeouts: Timeouts,
+    /// Time spent waiting for the network in the current speed window
+    waited: Duration,
+    bytes: u64,
+}
+
+impl BodyWatch {
+    /// Waits for the next chunk of the body
+    pub async fn next<S>(&mut self, stream: &mut S) -> Result<Option<Bytes>>
+    where
+        S: Stream<Item = reqwest::Result<Bytes>> + Unpin,
+    {
+        let started = Instant::now();
+        let chunk = match tokio::time::timeout(self.timeouts.stall, stream.next()).await {
+            Ok(Some(chunk)) => chunk.context("Failed to read chunk")?,
+            Ok(None) => return Ok(None),
+            Err(_) => return Err(TransferTimeout::Stalled(self.timeouts.stall).into()),
+        };
+        self.on_chunk(chunk.len() as u64, started.elapsed())?;
+        Ok(Some(chunk))
+    }
+
+    /// Only time spent waiting for data counts, so pauses of our own, like the bandwidth
+    /// limit, don't make a transfer look slow
+    fn on_chunk(&mut self, bytes: u64, waited: Duration) -> Result<(), TransferTimeout> {
+        self.waited += waited;
+        self.bytes += bytes;
+        if self.waited < self.timeouts.stall {
+            return Ok(());
+        }
+
+        let speed = (self.bytes as f64 / self.waited.as_secs_f64()) as u64;
+        self.waited = Duration::ZERO;
+        self.bytes = 0;
+        match self.timeouts.min_speed {
+            Some(min_speed) if speed < min_speed => {
+                Err(TransferTimeout::TooSlow { speed, min_speed })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeouts(min_speed: Option<u64>) -> Timeouts {
+        Timeouts {
+            connect: Duration::from_secs(10),
+            stall: Duration::from_secs(30),
+            min_speed,
+        }
+    }
+
+    #[test]
+    fn test_min_speed() {
+        let mut body = timeouts(Some(10_000)).body();
+        // judged once a whole window was spent waiting for data
+        assert!(body.on_chunk(100_000, Duration::from_secs(20)).is_ok());
+        assert!(matches!(
+            body.on_chunk(100_000, Duration::from_secs(20)),
+            Err(TransferTimeout::TooSlow { speed: 5000, .. })
+        ));
+
+        // a fast window passes and starts the next one
+        assert!(body.on_chunk(1_000_000, Duration::from_secs(30)).is_ok());
+        assert!(body.on_chunk(1000, Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_no_min_speed() {
+        let mut body = timeouts(None).body();
+        assert!(body.on_chunk(1, Duration::from_secs(60)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_body() {
+        let timeouts = Timeouts {
+            stall: Duration::from_millis(50),
+            ..timeouts(None)
+        };
+        let mut body = timeouts.body();
+        let mut stream = futures::stream::iter([Ok(Bytes::from_static(b"data"))])
+            .chain(futures::stream::pending());
+
+        assert_eq!(body.next(&mut stream).await.unwrap().unwrap(), "data");
+        let err = body.next(&mut stream).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransferTimeout>(),
+            Some(TransferTimeout::Stalled(_))
+        ));
+    }
+}
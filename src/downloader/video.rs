@@ -0,0 +1,140 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use indicatif::ProgressBar;
+use std::path::Path;
+use tidlers::client::{TidalClient, models::video::VideoPlaybackInfo};
+
+use super::limiter::HttpError;
+use super::parts::{finish_part, part_path};
+use super::{Downloader, JobItem, TrackJob, split_track_path, video_values};
+use crate::hls;
+use crate::types::VideoQuality;
+
+/// Extensions a downloaded video can end up with, depending on the HLS segment format
+const VIDEO_EXTENSIONS: [&str; 2] = ["mp4", "ts"];
+
+impl Downloader {
+    /// Plans a video downloaded on its own, outside of any album or playlist
+    pub(super) async fn prepare_video(
+        &self,
+        client: &mut TidalClient,
+        video_id: &str,
+    ) -> Result<TrackJob> {
+        let video = client
+            .get_video(video_id.to_string())
+            .await
+            .context("Failed to get video info")?;
+
+        println!("video: {}", video.title);
+        println!("artist: {}", video.artist.name);
+
+        let relative = self
+            .naming
+            .video_template()
+            .render(&video_values(&video, video.track_number));
+        let (dir, base_name) = split_track_path(&self.output_dir, &relative, video.id);
+
+        Ok(TrackJob {
+            label: relative.display().to_string(),
+            item: JobItem::Video(video),
+            dir,
+            base_name,
+        })
+    }
+
+    /// Downloads the HLS stream of a video and joins its segments into one file
+    ///
+    /// MPEG-TS segments are concatenated into a `.ts` file, fragmented MP4 segments are
+    /// written behind their init segment into an `.mp4` file. Interrupted downloads resume
+    /// after the last complete segment, like DASH tracks.
+    pub(super) async fn download_video_pb(
+        &self,
+        playback_info: &VideoPlaybackInfo,
+        output_dir: &Path,
+        base_name: &str,
+        pb: Option<&ProgressBar>,
+    ) -> Result<bool> {
+        if video_exists(output_dir, base_name) {
+            return Ok(false); // file was skipped
+        }
+        let claim = self.quota.start_download()?;
+
+        let max_height = match self.video_quality {
+            Some(VideoQuality::MaxHeight(height)) => Some(height),
+            _ => None,
+        };
+
+        let master_url = master_playlist_url(playback_info)?;
+        let master = self.fetch_text(&master_url).await?;
+        let variants = hls::parse_master_playlist(&master, &master_url)?;
+        let variant =
+            hls::select_variant(&variants, max_height).context("No video variant available")?;
+
+        let media = self.fetch_text(&variant.url).await?;
+        let playlist = hls::parse_media_playlist(&media, &variant.url)?;
+
+        let extension = if playlist.init_url.is_some() {
+            "mp4"
+        } else {
+            "ts"
+        };
+        let output_path = output_dir.join(format!("{}.{}", base_name, extension));
+        tokio::fs::create_dir_all(output_dir)
+            .await
+            .context("Failed to create video directory")?;
+
+        // a failed download keeps its partial data so the next attempt can resume it
+        let part_path = part_path(&output_path);
+        let urls: Vec<&str> = playlist.segment_urls.iter().map(String::as_str).collect();
+        self.download_segments_pb(playlist.init_url.as_deref(), &urls, &part_path, pb)
+            .await?;
+        finish_part(&part_path, &output_path)?;
+
+        claim.complete();
+        Ok(true) // file was downloaded
+    }
+
+    /// Fetches a playlist, which doesn't count as media data against the bandwidth cap
+    /// and the byte limit
+    async fn fetch_text(&self, url: &str) -> Result<String> {
+        self.cdn_limiter.wait().await;
+        let response = self.timeouts.send(self.http_client.get(url)).await?;
+        if !response.status().is_success() {
+            let error = HttpError::from_response(&response).into();
+            self.cdn_limiter.on_error(&error);
+            return Err(error);
+        }
+        self.cdn_limiter.on_success();
+
+        let mut data = Vec::new();
+        let mut stream = response.bytes_stream();
+        let mut body = self.timeouts.body();
+        while let Some(chunk) = body.next(&mut stream).await? {
+            data.extend_from_slice(&chunk);
+        }
+        String::from_utf8(data).context("Playlist is not valid UTF-8")
+    }
+}
+
+/// Whether the video was downloaded before, in either container
+pub(super) fn video_exists(output_dir: &Path, base_name: &str) -> bool {
+    VIDEO_EXTENSIONS
+        .iter()
+        .any(|ext| output_dir.join(format!("{}.{}", base_name, ext)).exists())
+}
+
+/// Extracts the HLS master playlist URL from the base64 encoded video manifest
+fn master_playlist_url(playback_info: &VideoPlaybackInfo) -> Result<String> {
+    let manifest = base64::engine::general_purpose::STANDARD
+        .decode(playback_info.manifest.trim())
+        .context("Failed to decode video manifest")?;
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&manifest).context("Failed to parse video manifest")?;
+
+    manifest["urls"]
+        .as_array()
+        .and_then(|urls| urls.first())
+        .and_then(|url| url.as_str())
+        .map(str::to_string)
+        .context("No URLs in video manifest")
+}
@@ -0,0 +1,165 @@
+use anyhow::{Context, Result, bail};
+use reqwest::Url;
+
+/// One rendition listed in an HLS master playlist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    pub bandwidth: u64,
+    pub height: u32,
+    pub url: String,
+}
+
+/// Segments of an HLS media playlist, in playback order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaPlaylist {
+    /// `EXT-X-MAP` initialization segment, present for fragmented MP4 streams
+    pub init_url: Option<String>,
+    pub segment_urls: Vec<String>,
+}
+
+/// Parses the variants of a master playlist, resolving URIs against `base_url`
+pub fn parse_master_playlist(text: &str, base_url: &str) -> Result<Vec<Variant>> {
+    let base = Url::parse(base_url).context("Invalid playlist URL")?;
+    let mut variants = Vec::new();
+    let mut pending: Option<(u64, u32)> = None;
+
+    for line in text.lines().map(str::trim) {
+        if let Some(attributes) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            let bandwidth = attribute(attributes, "BANDWIDTH")
+                .and_then(|b| b.parse().ok())
+                .unwrap_or(0);
+            let height = attribute(attributes, "RESOLUTION")
+                .and_then(|r| r.split_once('x'))
+                .and_then(|(_, h)| h.parse().ok())
+                .unwrap_or(0);
+            pending = Some((bandwidth, height));
+        } else if !line.is_empty()
+            && !line.starts_with('#')
+            && let Some((bandwidth, height)) = pending.take()
+        {
+            variants.push(Variant {
+                bandwidth,
+                height,
+                url: base.join(line).context("Invalid variant URI")?.to_string(),
+            });
+        }
+    }
+
+    if variants.is_empty() {
+        bail!("No variants in master playlist");
+    }
+    Ok(variants)
+}
+
+/// Picks the best variant not taller than `max_height`, or the smallest one if all are
+pub fn select_variant(variants: &[Variant], max_height: Option<u32>) -> Option<&Variant> {
+    let rank = |v: &&Variant| (v.height, v.bandwidth);
+    variants
+        .iter()
+        .filter(|v| max_height.is_none_or(|max| v.height <= max))
+        .max_by_key(rank)
+        .or_else(|| variants.iter().min_by_key(rank))
+}
+
+/// Parses a media playlist, resolving URIs against `base_url`
+pub fn parse_media_playlist(text: &str, base_url: &str) -> Result<MediaPlaylist> {
+    let base = Url::parse(base_url).context("Invalid playlist URL")?;
+    let mut init_url = None;
+    let mut segment_urls = Vec::new();
+
+    for line in text.lines().map(str::trim) {
+        if let Some(attributes) = line.strip_prefix("#EXT-X-MAP:") {
+            let uri = attribute(attributes, "URI").context("EXT-X-MAP without URI")?;
+            init_url = Some(base.join(uri).context("Invalid init URI")?.to_string());
+        } else if line.starts_with("#EXT-X-KEY:") && !line.contains("METHOD=NONE") {
+            bail!("Encrypted HLS streams are not supported");
+        } else if !line.is_empty() && !line.starts_with('#') {
+            segment_urls.push(base.join(line).context("Invalid segment URI")?.to_string());
+        }
+    }
+
+    if segment_urls.is_empty() {
+        bail!("No segments in media playlist");
+    }
+    Ok(MediaPlaylist {
+        init_url,
+        segment_urls,
+    })
+}
+
+/// Reads `NAME=value` or `NAME="value"` from an attribute list
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = attributes;
+    while !rest.is_empty() {
+        let (key, after) = rest.split_once('=')?;
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], quoted[end + 1..].trim_start_matches(','))
+            }
+            None => match after.split_once(',') {
+                Some((value, next)) => (value, next),
+                None => (after, ""),
+            },
+        };
+        if key.trim() == name {
+            return Some(value);
+        }
+        rest = next;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=1500000,CODECS=\"avc1.4d401f,mp4a.40.2\",RESOLUTION=640x360
+360/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=6000000,CODECS=\"avc1.640028,mp4a.40.2\",RESOLUTION=1920x1080
+1080/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=3000000,CODECS=\"avc1.4d401f,mp4a.40.2\",RESOLUTION=1280x720
+720/index.m3u8
+";
+
+    #[test]
+    fn test_select_variant() {
+        let variants = parse_master_playlist(MASTER, "https://cdn.example/v/master.m3u8").unwrap();
+        assert_eq!(variants.len(), 3);
+
+        let best = select_variant(&variants, None).unwrap();
+        assert_eq!(best.url, "https://cdn.example/v/1080/index.m3u8");
+
+        let capped = select_variant(&variants, Some(720)).unwrap();
+        assert_eq!(capped.height, 720);
+
+        let smallest = select_variant(&variants, Some(240)).unwrap();
+        assert_eq!(smallest.height, 360);
+    }
+
+    #[test]
+    fn test_parse_media_playlist() {
+        let text = "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXTINF:10.0,
+seg1.mp4
+#EXTINF:4.5,
+https://other.example/seg2.mp4
+#EXT-X-ENDLIST
+";
+        let playlist = parse_media_playlist(text, "https://cdn.example/v/720/index.m3u8").unwrap();
+        assert_eq!(
+            playlist.init_url.as_deref(),
+            Some("https://cdn.example/v/720/init.mp4")
+        );
+        assert_eq!(
+            playlist.segment_urls,
+            vec![
+                "https://cdn.example/v/720/seg1.mp4",
+                "https://other.example/seg2.mp4"
+            ]
+        );
+    }
+}
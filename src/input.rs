@@ -0,0 +1,174 @@
+use anyhow::{Context, Result, bail};
+use clap::Parser;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::types::MediaType;
+use crate::{MediaTypeArg, QualityArg, parse_tidal_input};
+
+/// One thing to download, as given on the command line or in an input file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Input {
+    pub id: String,
+    pub media_type: MediaType,
+    pub quality: QualityArg,
+}
+
+/// A line of an input file: a URL or ID, optionally followed by per-line overrides
+#[derive(Parser)]
+#[command(no_binary_name = true)]
+struct InputLine {
+    #[arg(value_name = "URL_OR_ID")]
+    id: String,
+
+    #[arg(short, long, value_enum)]
+    media_type: Option<MediaTypeArg>,
+
+    #[arg(short, long, value_enum)]
+    quality: Option<QualityArg>,
+}
+
+/// Collects the inputs from the command line and input files into one queue
+///
+/// `-` reads inputs from stdin, both as a positional argument and as an input file.
+/// Inputs that resolve to the same media are only downloaded once.
+pub fn collect_inputs(
+    args: &[String],
+    input_files: &[PathBuf],
+    media_type: MediaTypeArg,
+    quality: QualityArg,
+) -> Result<Vec<Input>> {
+    let mut inputs = Vec::new();
+
+    for arg in args {
+        if arg == "-" {
+            inputs.extend(read_inputs(Path::new("-"), media_type, quality)?);
+        } else {
+            inputs.push(resolve(arg, media_type, quality));
+        }
+    }
+
+    for path in input_files {
+        inputs.extend(read_inputs(path, media_type, quality)?);
+    }
+
+    let mut unique: Vec<Input> = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        if !unique
+            .iter()
+            .any(|u| u.id == input.id && u.media_type == input.media_type)
+        {
+            unique.push(input);
+        }
+    }
+
+    if unique.is_empty() {
+        bail!("No URLs or IDs to download");
+    }
+    Ok(unique)
+}
+
+fn read_inputs(path: &Path, media_type: MediaTypeArg, quality: QualityArg) -> Result<Vec<Input>> {
+    let reader: Box<dyn BufRead> = if path == Path::new("-") {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open input file {}", path.display()))?;
+        Box::new(BufReader::new(file))
+    };
+
+    let mut inputs = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line.context("Failed to read input file")?;
+        let input = parse_line(&line, media_type, quality)
+            .with_context(|| format!("{}:{}", path.display(), number + 1))?;
+        inputs.extend(input);
+    }
+    Ok(inputs)
+}
+
+/// Parses one input line, returning `None` for blank lines and `#` comments
+fn parse_line(line: &str, media_type: MediaTypeArg, quality: QualityArg) -> Result<Option<Input>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let parsed = InputLine::try_parse_from(line.split_whitespace())
+        .map_err(|e| anyhow::anyhow!(e.render().to_string().trim().to_string()))?;
+
+    Ok(Some(resolve(
+        &parsed.id,
+        parsed.media_type.unwrap_or(media_type),
+        parsed.quality.unwrap_or(quality),
+    )))
+}
+
+fn resolve(input: &str, media_type: MediaTypeArg, quality: QualityArg) -> Input {
+    let (id, detected_type) = parse_tidal_input(input);
+
+    let media_type = match media_type {
+        MediaTypeArg::Auto => detected_type,
+        MediaTypeArg::Track => MediaType::Track,
+        MediaTypeArg::Album => MediaType::Album,
+        MediaTypeArg::Playlist => MediaType::Playlist,
+        MediaTypeArg::Artist => MediaType::Artist,
+        MediaTypeArg::Mix => MediaType::Mix,
+        MediaTypeArg::Video => MediaType::Video,
+    };
+
+    Input {
+        id,
+        media_type,
+        quality,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line_skips_comments() {
+        let parse = |line| parse_line(line, MediaTypeArg::Auto, QualityArg::HiRes).unwrap();
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("   "), None);
+        assert_eq!(parse("# nightly albums"), None);
+    }
+
+    #[test]
+    fn test_parse_line_overrides() {
+        let input = parse_line(
+            "  https://tidal.com/album/55130630 --quality lossless",
+            MediaTypeArg::Auto,
+            QualityArg::HiRes,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(input.id, "55130630");
+        assert_eq!(input.media_type, MediaType::Album);
+        assert_eq!(input.quality, QualityArg::Lossless);
+
+        let input = parse_line("3346 -m artist", MediaTypeArg::Auto, QualityArg::High)
+            .unwrap()
+            .unwrap();
+        assert_eq!(input.media_type, MediaType::Artist);
+        assert_eq!(input.quality, QualityArg::High);
+    }
+
+    #[test]
+    fn test_parse_line_rejects_unknown_options() {
+        assert!(parse_line("437468401 --bogus", MediaTypeArg::Auto, QualityArg::HiRes).is_err());
+    }
+
+    #[test]
+    fn test_collect_inputs_removes_duplicates() {
+        let args = [
+            "https://tidal.com/track/437468401".to_string(),
+            "437468401".to_string(),
+            "https://tidal.com/album/55130630".to_string(),
+        ];
+        let inputs = collect_inputs(&args, &[], MediaTypeArg::Auto, QualityArg::HiRes).unwrap();
+        assert_eq!(inputs.len(), 2);
+    }
+}
@@ -1,20 +1,39 @@
 use anyhow::{Context, Result};
 use clap::{Parser, ValueEnum};
+use std::time::Duration;
 use tidlers::client::models::playback::AudioQuality;
 
+mod archive;
 mod args;
 mod auth;
+mod cover;
+mod dash;
 mod downloader;
+mod hls;
+mod input;
+mod metadata;
+mod mp4box;
+mod remux;
+mod rewrite;
+mod tagging;
+mod template;
 mod types;
+mod verify;
 
 use auth::{authenticate, load_or_authenticate};
-use downloader::Downloader;
-use types::MediaType;
+use cover::CoverSize;
+use downloader::{DownloadOptions, Downloader, Timeouts};
+use input::collect_inputs;
+use template::{
+    ALBUM_FIELDS, DiscLayout, MIX_FIELDS, Naming, PLAYLIST_FIELDS, TRACK_FIELDS, Template,
+};
+use types::{DashContainer, Favorites, MediaType, ReleaseFilter, ReleaseType, VideoQuality};
 
-use crate::args::Cli;
+use crate::archive::Archive;
+use crate::args::{ArchiveCommand, Cli, Command};
 
 #[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
-enum QualityArg {
+pub enum QualityArg {
     Low,
     High,
     Lossless,
@@ -27,6 +46,128 @@ enum MediaTypeArg {
     Track,
     Album,
     Playlist,
+    Artist,
+    Mix,
+    Video,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum FavoritesArg {
+    Tracks,
+    Albums,
+    Playlists,
+    Artists,
+}
+
+impl From<FavoritesArg> for Favorites {
+    fn from(val: FavoritesArg) -> Self {
+        match val {
+            FavoritesArg::Tracks => Favorites::Tracks,
+            FavoritesArg::Albums => Favorites::Albums,
+            FavoritesArg::Playlists => Favorites::Playlists,
+            FavoritesArg::Artists => Favorites::Artists,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum VideoQualityArg {
+    None,
+    #[value(name = "360")]
+    Px360,
+    #[value(name = "480")]
+    Px480,
+    #[value(name = "720")]
+    Px720,
+    #[value(name = "1080")]
+    Px1080,
+    Best,
+}
+
+impl From<VideoQualityArg> for Option<VideoQuality> {
+    fn from(val: VideoQualityArg) -> Self {
+        match val {
+            VideoQualityArg::None => None,
+            VideoQualityArg::Px360 => Some(VideoQuality::MaxHeight(360)),
+            VideoQualityArg::Px480 => Some(VideoQuality::MaxHeight(480)),
+            VideoQualityArg::Px720 => Some(VideoQuality::MaxHeight(720)),
+            VideoQualityArg::Px1080 => Some(VideoQuality::MaxHeight(1080)),
+            VideoQualityArg::Best => Some(VideoQuality::Best),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum DashContainerArg {
+    Flac,
+    M4a,
+}
+
+impl From<DashContainerArg> for DashContainer {
+    fn from(val: DashContainerArg) -> Self {
+        match val {
+            DashContainerArg::Flac => DashContainer::Flac,
+            DashContainerArg::M4a => DashContainer::M4a,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum ReleaseTypeArg {
+    Album,
+    Ep,
+    Single,
+    Compilation,
+}
+
+impl From<ReleaseTypeArg> for ReleaseType {
+    fn from(val: ReleaseTypeArg) -> Self {
+        match val {
+            ReleaseTypeArg::Album => ReleaseType::Album,
+            ReleaseTypeArg::Ep => ReleaseType::Ep,
+            ReleaseTypeArg::Single => ReleaseType::Single,
+            ReleaseTypeArg::Compilation => ReleaseType::Compilation,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum CoverSizeArg {
+    None,
+    #[value(name = "320")]
+    Px320,
+    #[value(name = "640")]
+    Px640,
+    #[value(name = "1280")]
+    Px1280,
+    Original,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum DiscLayoutArg {
+    Subfolders,
+    Prefix,
+}
+
+impl From<DiscLayoutArg> for DiscLayout {
+    fn from(val: DiscLayoutArg) -> Self {
+        match val {
+            DiscLayoutArg::Subfolders => DiscLayout::Subfolders,
+            DiscLayoutArg::Prefix => DiscLayout::Prefix,
+        }
+    }
+}
+
+impl From<CoverSizeArg> for Option<CoverSize> {
+    fn from(val: CoverSizeArg) -> Self {
+        match val {
+            CoverSizeArg::None => None,
+            CoverSizeArg::Px320 => Some(CoverSize::Pixels(320)),
+            CoverSizeArg::Px640 => Some(CoverSize::Pixels(640)),
+            CoverSizeArg::Px1280 => Some(CoverSize::Pixels(1280)),
+            CoverSizeArg::Original => Some(CoverSize::Original),
+        }
+    }
 }
 
 impl From<QualityArg> for AudioQuality {
@@ -44,6 +185,30 @@ impl From<QualityArg> for AudioQuality {
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    // verifying files needs neither inputs nor a TIDAL session
+    if let Some(Command::Verify { dir }) = &cli.command {
+        return verify::verify_dir(dir);
+    }
+
+    // a broken archive should stop the run before anything is downloaded twice
+    let archive = Archive::open(&cli.download_archive)?;
+    if let Some(Command::Archive { action }) = &cli.command {
+        return match action {
+            ArchiveCommand::List => {
+                archive::list(&archive);
+                Ok(())
+            }
+            ArchiveCommand::Import { dir } => archive::import(&archive, dir),
+            ArchiveCommand::Prune => archive::prune(&archive),
+        };
+    }
+
+    // read every input up front so a bad input file fails before authenticating
+    let inputs = match cli.command {
+        Some(_) => Vec::new(),
+        None => collect_inputs(&cli.ids, &cli.input_file, cli.media_type, cli.quality)?,
+    };
+
     // authenticate
     let mut client = if cli.reauth {
         println!("forcing re-authentication...\n");
@@ -56,38 +221,84 @@ async fn main() -> Result<()> {
     client.set_audio_quality(cli.quality.into());
     println!("audio quality: {:?}\n", cli.quality);
 
-    // parse ID and determine media type
-    let (media_id, detected_type) = parse_tidal_input(&cli.id);
-
-    let media_type = match cli.media_type {
-        MediaTypeArg::Auto => detected_type,
-        MediaTypeArg::Track => MediaType::Track,
-        MediaTypeArg::Album => MediaType::Album,
-        MediaTypeArg::Playlist => MediaType::Playlist,
-    };
-
-    println!("media type: {:?}", media_type);
     println!("output directory: {}\n", cli.output.display());
 
     // create output directory
     std::fs::create_dir_all(&cli.output).context("Failed to create output directory")?;
 
+    // parse naming templates
+    let naming = Naming {
+        album: Template::parse(&cli.album_template, ALBUM_FIELDS)
+            .context("Invalid album template")?,
+        playlist: Template::parse(&cli.playlist_template, PLAYLIST_FIELDS)
+            .context("Invalid playlist template")?,
+        mix: Template::parse(&cli.mix_template, MIX_FIELDS).context("Invalid mix template")?,
+        track: cli
+            .track_template
+            .as_deref()
+            .map(|t| Template::parse(t, TRACK_FIELDS))
+            .transpose()
+            .context("Invalid track template")?,
+        disc_layout: cli.disc_layout.into(),
+    };
+
     // create downloader
-    let downloader = Downloader::new(cli.output, cli.parallel);
+    let downloader = Downloader::new(
+        cli.output,
+        naming,
+        DownloadOptions {
+            max_parallel: cli.parallel,
+            segment_parallel: cli.segment_parallel,
+            adaptive: cli.adaptive,
+            quality: cli.quality,
+            cover_size: cli.cover_size.into(),
+            cover_file: cli.cover_file,
+            video_quality: cli.video_quality.into(),
+            dash_container: cli.dash_container.into(),
+            verify: !cli.no_verify,
+            limit_rate: cli.limit_rate,
+            max_downloads: cli.max_downloads,
+            max_bytes: cli.max_bytes,
+            timeouts: Timeouts {
+                connect: Duration::from_secs(cli.connect_timeout),
+                stall: Duration::from_secs(cli.stall_timeout),
+                min_speed: cli.min_speed,
+            },
+            session_file: cli.session_file,
+            archive,
+            prefetch: cli.prefetch,
+            api_rate: cli.api_rate,
+        },
+    )?;
+
+    // clean up files of interrupted runs, they would never be finished otherwise
+    let removed = downloader.remove_stale_parts()?;
+    if removed > 0 {
+        println!("removed {} unfinished files from a previous run\n", removed);
+    }
 
-    // download based on type
-    match media_type {
-        MediaType::Track => {
-            println!("downloading track {}...\n", media_id);
-            downloader.download_track(&mut client, &media_id).await?;
+    let filter = ReleaseFilter {
+        release_types: cli.release_types.into_iter().map(Into::into).collect(),
+        released_after: cli.released_after,
+        released_before: cli.released_before,
+    };
+
+    match cli.command {
+        Some(Command::Favorites { kind }) => {
+            let favorites: Favorites = kind.into();
+            println!("downloading favorite {:?}...\n", favorites);
+            downloader
+                .download_favorites(&mut client, favorites, &filter)
+                .await?;
         }
-        MediaType::Album => {
-            println!("downloading album {}...\n", media_id);
-            downloader.download_album(&mut client, &media_id).await?;
+        Some(Command::Verify { .. } | Command::Archive { .. }) => {
+            unreachable!("verify and archive return before authenticating")
         }
-        MediaType::Playlist => {
-            println!("downloading playlist {}...\n", media_id);
-            downloader.download_playlist(&mut client, &media_id).await?;
+        None => {
+            println!("inputs: {}", inputs.len());
+            downloader
+                .download_inputs(&mut client, &inputs, &filter)
+                .await?;
         }
     }
 
@@ -104,7 +315,11 @@ async fn main() -> Result<()> {
 /// - https://tidal.com/album/55130630/u
 /// - https://tidal.com/album/55130630
 /// - https://tidal.com/playlist/aa692128-2954-4fe1-b5a1-4ede1add485d
-/// - Raw IDs: 437468401, 55130630, aa692128-2954-4fe1-b5a1-4ede1add485d
+/// - https://tidal.com/artist/3346
+/// - https://tidal.com/mix/0017159e6a1f34ae3d981792d72ecf
+/// - https://tidal.com/video/75623239
+/// - Raw IDs: 437468401, 55130630, aa692128-2954-4fe1-b5a1-4ede1add485d,
+///   0017159e6a1f34ae3d981792d72ecf
 fn parse_tidal_input(input: &str) -> (String, MediaType) {
     // Check if it's a URL
     if input.starts_with("http://") || input.starts_with("https://") {
@@ -122,6 +337,9 @@ fn parse_tidal_input(input: &str) -> (String, MediaType) {
     } else if input.parse::<u64>().is_ok() {
         // Numeric IDs - default to track
         (input.to_string(), MediaType::Track)
+    } else if input.len() >= 20 && input.chars().all(|c| c.is_ascii_hexdigit()) {
+        // Long hex strings are mix IDs
+        (input.to_string(), MediaType::Mix)
     } else {
         // Unknown format - default to track
         (input.to_string(), MediaType::Track)
@@ -149,6 +367,9 @@ fn parse_tidal_url(url: &str) -> Option<(String, MediaType)> {
         "track" => MediaType::Track,
         "album" => MediaType::Album,
         "playlist" => MediaType::Playlist,
+        "artist" => MediaType::Artist,
+        "mix" => MediaType::Mix,
+        "video" => MediaType::Video,
         _ => return None,
     };
 
@@ -188,6 +409,104 @@ mod tests {
         assert!(matches!(media_type, MediaType::Playlist));
     }
 
+    #[test]
+    fn test_parse_artist_url() {
+        let (id, media_type) = parse_tidal_input("https://tidal.com/artist/3346");
+        assert_eq!(id, "3346");
+        assert!(matches!(media_type, MediaType::Artist));
+    }
+
+    #[test]
+    fn test_parse_mix_url() {
+        let (id, media_type) =
+            parse_tidal_input("https://tidal.com/browse/mix/0017159e6a1f34ae3d981792d72ecf");
+        assert_eq!(id, "0017159e6a1f34ae3d981792d72ecf");
+        assert!(matches!(media_type, MediaType::Mix));
+    }
+
+    #[test]
+    fn test_parse_mix_id() {
+        let (id, media_type) = parse_tidal_input("0017159e6a1f34ae3d981792d72ecf");
+        assert_eq!(id, "0017159e6a1f34ae3d981792d72ecf");
+        assert!(matches!(media_type, MediaType::Mix));
+    }
+
+    #[test]
+    fn test_parse_video_url() {
+        let (id, media_type) = parse_tidal_input("https://tidal.com/video/75623239/u");
+        assert_eq!(id, "75623239");
+        assert!(matches!(media_type, MediaType::Video));
+    }
+
+    #[test]
+    fn test_favorites_subcommand() {
+        let cli = Cli::try_parse_from(["yadal", "-q", "lossless", "favorites", "albums"]).unwrap();
+        assert!(cli.ids.is_empty());
+        assert!(matches!(
+            cli.command,
+            Some(Command::Favorites {
+                kind: FavoritesArg::Albums
+            })
+        ));
+
+        // a URL is still required without a subcommand
+        assert!(Cli::try_parse_from(["yadal"]).is_err());
+    }
+
+    #[test]
+    fn test_archive_subcommand() {
+        let cli = Cli::try_parse_from([
+            "yadal",
+            "--download-archive",
+            "library.jsonl",
+            "archive",
+            "import",
+            "./music",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.download_archive,
+            std::path::PathBuf::from("library.jsonl")
+        );
+        assert!(matches!(
+            cli.command,
+            Some(Command::Archive {
+                action: ArchiveCommand::Import { .. }
+            })
+        ));
+    }
+
+    #[test]
+    fn test_multiple_inputs() {
+        let cli = Cli::try_parse_from(["yadal", "437468401", "-", "-i", "urls.txt"]).unwrap();
+        assert_eq!(cli.ids, ["437468401", "-"]);
+        assert_eq!(cli.input_file, [std::path::PathBuf::from("urls.txt")]);
+
+        // an input file alone is enough
+        assert!(Cli::try_parse_from(["yadal", "--input-file", "urls.txt"]).is_ok());
+    }
+
+    #[test]
+    fn test_size_options() {
+        let cli = Cli::try_parse_from([
+            "yadal",
+            "--limit-rate",
+            "1.5M",
+            "--max-bytes",
+            "20g",
+            "--max-downloads",
+            "3",
+            "437468401",
+        ])
+        .unwrap();
+        assert_eq!(cli.limit_rate, Some(1536 * 1024));
+        assert_eq!(cli.max_bytes, Some(20 << 30));
+        assert_eq!(cli.max_downloads, Some(3));
+
+        assert!(Cli::try_parse_from(["yadal", "--limit-rate", "5X", "437468401"]).is_err());
+        assert!(Cli::try_parse_from(["yadal", "--max-bytes", "0", "437468401"]).is_err());
+    }
+
     #[test]
     fn test_parse_numeric_id() {
         let (id, media_type) = parse_tidal_input("437468401");
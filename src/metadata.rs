@@ -0,0 +1,69 @@
+use tidlers::client::models::{album::Album, track::Track};
+
+use crate::cover::Cover;
+
+/// Tag values for a single downloaded track
+#[derive(Debug, Clone, Default)]
+pub struct TrackMetadata {
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub album_artist: Option<String>,
+    pub track_number: u32,
+    pub track_total: Option<u32>,
+    pub disc_number: u32,
+    pub disc_total: Option<u32>,
+    pub date: Option<String>,
+    pub isrc: Option<String>,
+    pub copyright: Option<String>,
+    pub tidal_track_id: u64,
+    pub tidal_album_id: u64,
+    /// Track length in seconds, as reported by TIDAL
+    pub duration: u32,
+    pub cover: Option<Cover>,
+}
+
+impl TrackMetadata {
+    /// Builds metadata from a track, using the full album info when it was fetched
+    pub fn new(track: &Track, album: Option<&Album>) -> Self {
+        let title = match track.version.as_deref().map(str::trim) {
+            Some(version) if !version.is_empty() => format!("{} ({})", track.title, version),
+            _ => track.title.clone(),
+        };
+
+        let mut artists: Vec<String> = track.artists.iter().map(|a| a.name.clone()).collect();
+        if artists.is_empty() {
+            artists.push(track.artist.name.clone());
+        }
+
+        // album-level values are only known when the album itself was fetched
+        let (album_artist, track_total, disc_total, date) = match album {
+            Some(album) => (
+                Some(album.artist.name.clone()),
+                // TRACKTOTAL is per disc, which the album only tells us for single-disc releases
+                (album.number_of_volumes <= 1).then_some(album.number_of_tracks),
+                Some(album.number_of_volumes),
+                album.release_date.clone(),
+            ),
+            None => (None, None, None, track.stream_start_date.clone()),
+        };
+
+        Self {
+            title,
+            artists,
+            album: track.album.title.clone(),
+            album_artist,
+            track_number: track.track_number,
+            track_total,
+            disc_number: track.volume_number,
+            disc_total,
+            date: date.map(|d| d.chars().take(10).collect()),
+            isrc: track.isrc.clone().filter(|s| !s.is_empty()),
+            copyright: track.copyright.clone().filter(|s| !s.is_empty()),
+            tidal_track_id: track.id,
+            tidal_album_id: track.album.id,
+            duration: track.duration,
+            cover: None,
+        }
+    }
+}
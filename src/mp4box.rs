@@ -0,0 +1,347 @@
+use anyhow::{Context, Result, bail};
+use std::io::Read;
+
+/// Header of an MP4 box read from a stream, with its raw bytes for copying it unchanged
+pub struct BoxHeader {
+    pub box_type: [u8; 4],
+    pub header_len: u64,
+    /// Total box size including the header, `None` if the box runs to the end of the file
+    pub size: Option<u64>,
+    pub raw: Vec<u8>,
+}
+
+/// Reads the next box header, `None` at the end of the stream
+pub fn read_box_header<R: Read>(reader: &mut R) -> Result<Option<BoxHeader>> {
+    let mut raw = vec![0u8; 8];
+    let mut filled = 0;
+    while filled < raw.len() {
+        let n = reader.read(&mut raw[filled..])?;
+        if n == 0 {
+            if filled == 0 {
+                return Ok(None);
+            }
+            bail!("Truncated box header");
+        }
+        filled += n;
+    }
+
+    let box_type = [raw[4], raw[5], raw[6], raw[7]];
+    let size32 = u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]);
+
+    let (header_len, size) = match size32 {
+        0 => (8, None),
+        1 => {
+            let mut large = [0u8; 8];
+            reader
+                .read_exact(&mut large)
+                .context("Truncated box header")?;
+            raw.extend_from_slice(&large);
+            (16, Some(u64::from_be_bytes(large)))
+        }
+        n => (8, Some(n as u64)),
+    };
+
+    if size.is_some_and(|s| s < header_len) {
+        bail!("Invalid size for '{}' box", type_name(&box_type));
+    }
+
+    Ok(Some(BoxHeader {
+        box_type,
+        header_len,
+        size,
+        raw,
+    }))
+}
+
+/// Reads the rest of a box after its header
+pub fn read_payload<R: Read>(reader: &mut R, header: &BoxHeader) -> Result<Vec<u8>> {
+    let len = header.size.unwrap_or(header.header_len) - header.header_len;
+    let mut payload = vec![0u8; len as usize];
+    reader
+        .read_exact(&mut payload)
+        .with_context(|| format!("Truncated '{}' box", type_name(&header.box_type)))?;
+    Ok(payload)
+}
+
+/// Splits a buffer into its child boxes as (type, full box bytes)
+pub fn children(buf: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+
+    while pos < buf.len() {
+        let (box_type, _, size) = child_header(&buf[pos..])?;
+        boxes.push((box_type, &buf[pos..pos + size]));
+        pos += size;
+    }
+
+    Ok(boxes)
+}
+
+/// Parses an in-memory box header as (type, header length, total size)
+pub fn child_header(buf: &[u8]) -> Result<([u8; 4], usize, usize)> {
+    if buf.len() < 8 {
+        bail!("Truncated child box");
+    }
+
+    let box_type = [buf[4], buf[5], buf[6], buf[7]];
+    let (header_len, size) = match u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) {
+        0 => (8, buf.len()),
+        1 => {
+            if buf.len() < 16 {
+                bail!("Truncated child box");
+            }
+            (16, read_u64(buf, 8) as usize)
+        }
+        n => (8, n as usize),
+    };
+
+    if size < header_len || size > buf.len() {
+        bail!("Invalid size for '{}' box", type_name(&box_type));
+    }
+
+    Ok((box_type, header_len, size))
+}
+
+pub fn make_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 8);
+    out.extend_from_slice(&((payload.len() + 8) as u32).to_be_bytes());
+    out.extend_from_slice(box_type);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Builds a full box, whose payload starts with the version byte and 24 bits of flags
+#[cfg(test)]
+pub fn full_box(box_type: &[u8; 4], version_and_flags: u32, body: &[u8]) -> Vec<u8> {
+    let mut payload = version_and_flags.to_be_bytes().to_vec();
+    payload.extend_from_slice(body);
+    make_box(box_type, &payload)
+}
+
+pub fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+pub fn read_u64(buf: &[u8], at: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[at..at + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+pub fn type_name(box_type: &[u8; 4]) -> String {
+    String::from_utf8_lossy(box_type).into_owned()
+}
+
+/// Where a run of samples sits in the file and how long each sample is
+pub struct SampleRun {
+    pub offset: u64,
+    pub sizes: Vec<u32>,
+}
+
+/// Sample defaults from `moov/mvex/trex`, used when a fragment doesn't set its own
+#[derive(Default, Clone, Copy)]
+pub struct TrackDefaults {
+    pub duration: u32,
+    pub size: u32,
+}
+
+/// Reads the sample defaults of the first track from a `moov` payload
+pub fn track_defaults(moov: &[u8]) -> Result<TrackDefaults> {
+    let mut defaults = TrackDefaults::default();
+    if let Some(trex) = child_path(moov, &[b"mvex", b"trex"])? {
+        defaults.duration = be_u32(trex, 12)?;
+        defaults.size = be_u32(trex, 16)?;
+    }
+    Ok(defaults)
+}
+
+/// Most samples one track fragment run may hold when all of them use the default size,
+/// hours of audio at the usual frame lengths
+const MAX_RUN_SAMPLES: usize = 1 << 20;
+
+/// Reads the sample runs of a `moof` payload and their combined duration
+pub fn parse_moof(
+    moof: &[u8],
+    moof_offset: u64,
+    track: TrackDefaults,
+) -> Result<(Vec<SampleRun>, Option<u64>)> {
+    let mut runs = Vec::new();
+    let mut total_duration = Some(0u64);
+
+    for (box_type, traf) in children(moof)? {
+        if &box_type != b"traf" {
+            continue;
+        }
+        let (_, header_len, _) = child_header(traf)?;
+        let traf = &traf[header_len..];
+
+        let tfhd = child(traf, b"tfhd")?.context("No 'tfhd' box in track fragment")?;
+        let flags = be_u32(tfhd, 0)? & 0x00ff_ffff;
+        let mut pos = 8; // version, flags and track ID
+        let mut base = moof_offset;
+        let mut defaults = track;
+
+        if flags & 0x01 != 0 {
+            base = be_u64(tfhd, pos)?;
+            pos += 8;
+        }
+        if flags & 0x02 != 0 {
+            pos += 4; // sample description index
+        }
+        if flags & 0x08 != 0 {
+            defaults.duration = be_u32(tfhd, pos)?;
+            pos += 4;
+        }
+        if flags & 0x10 != 0 {
+            defaults.size = be_u32(tfhd, pos)?;
+        }
+
+        // without a data offset a run continues where the previous one ended
+        let mut next_offset = base;
+        for (box_type, trun) in children(traf)? {
+            if &box_type != b"trun" {
+                continue;
+            }
+            let (_, header_len, _) = child_header(trun)?;
+            let trun = &trun[header_len..];
+
+            let flags = be_u32(trun, 0)? & 0x00ff_ffff;
+            let count = be_u32(trun, 4)?;
+            let mut pos = 8;
+
+            let mut offset = next_offset;
+            if flags & 0x01 != 0 {
+                let data_offset = be_u32(trun, pos)? as i32;
+                offset = base
+                    .checked_add_signed(data_offset as i64)
+                    .context("Invalid sample data offset")?;
+                pos += 4;
+            }
+            if flags & 0x04 != 0 {
+                pos += 4; // first sample flags
+            }
+
+            // the count comes from the file, so it has to fit the box before anything is
+            // allocated for it
+            let per_sample = [0x100, 0x200, 0x400, 0x800]
+                .iter()
+                .filter(|&&field| flags & field != 0)
+                .count()
+                * 4;
+            if count as usize > MAX_RUN_SAMPLES
+                || count as usize * per_sample > trun.len().saturating_sub(pos)
+            {
+                bail!(
+                    "Track fragment run of {} samples doesn't fit its box",
+                    count
+                );
+            }
+
+            let mut sizes = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let mut duration = defaults.duration;
+                let mut size = defaults.size;
+                if flags & 0x100 != 0 {
+                    duration = be_u32(trun, pos)?;
+                    pos += 4;
+                }
+                if flags & 0x200 != 0 {
+                    size = be_u32(trun, pos)?;
+                    pos += 4;
+                }
+                if flags & 0x400 != 0 {
+                    pos += 4; // sample flags
+                }
+                if flags & 0x800 != 0 {
+                    pos += 4; // composition time offset
+                }
+
+                if size == 0 {
+                    bail!("Sample without a size in track fragment");
+                }
+                sizes.push(size);
+                total_duration = total_duration
+                    .filter(|_| duration > 0)
+                    .map(|total| total + duration as u64);
+            }
+
+            next_offset = offset + sizes.iter().map(|&s| s as u64).sum::<u64>();
+            runs.push(SampleRun { offset, sizes });
+        }
+    }
+
+    runs.sort_by_key(|run| run.offset);
+    Ok((runs, total_duration))
+}
+
+/// Payload of the first child box of the given type
+pub fn child<'a>(buf: &'a [u8], box_type: &[u8; 4]) -> Result<Option<&'a [u8]>> {
+    for (child_type, data) in children(buf)? {
+        if &child_type == box_type {
+            let (_, header_len, _) = child_header(data)?;
+            return Ok(Some(&data[header_len..]));
+        }
+    }
+    Ok(None)
+}
+
+/// Payload of the box at the end of a path of nested boxes
+pub fn child_path<'a>(buf: &'a [u8], path: &[&[u8; 4]]) -> Result<Option<&'a [u8]>> {
+    let mut current = buf;
+    for box_type in path {
+        match child(current, box_type)? {
+            Some(payload) => current = payload,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(current))
+}
+
+/// Reads a big-endian integer, failing instead of panicking on truncated boxes
+pub fn be_u32(buf: &[u8], at: usize) -> Result<u32> {
+    let bytes = buf.get(at..at + 4).context("Truncated MP4 box")?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+pub fn be_u64(buf: &[u8], at: usize) -> Result<u64> {
+    Ok((be_u32(buf, at)? as u64) << 32 | be_u32(buf, at + 4)? as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moof(trun_flags: u32, count: u32, body: &[u8]) -> Vec<u8> {
+        let tfhd = full_box(b"tfhd", 0, &1u32.to_be_bytes());
+        let mut trun_body = count.to_be_bytes().to_vec();
+        trun_body.extend_from_slice(body);
+        let trun = full_box(b"trun", trun_flags, &trun_body);
+        make_box(b"traf", &[tfhd, trun].concat())
+    }
+
+    #[test]
+    fn test_parse_moof() {
+        let defaults = TrackDefaults {
+            duration: 4096,
+            size: 0,
+        };
+        let sizes: Vec<u8> = [100u32, 200].iter().flat_map(|s| s.to_be_bytes()).collect();
+        let (runs, duration) = parse_moof(&moof(0x200, 2, &sizes), 1000, defaults).unwrap();
+        assert_eq!(runs[0].offset, 1000);
+        assert_eq!(runs[0].sizes, [100, 200]);
+        assert_eq!(duration, Some(8192));
+    }
+
+    #[test]
+    fn test_parse_moof_rejects_oversized_runs() {
+        let defaults = TrackDefaults {
+            duration: 4096,
+            size: 100,
+        };
+        // more per-sample sizes claimed than the box holds
+        let sizes = 100u32.to_be_bytes();
+        assert!(parse_moof(&moof(0x200, 1_000_000, &sizes), 0, defaults).is_err());
+        // default sizes only, with an absurd count
+        assert!(parse_moof(&moof(0, u32::MAX, &[]), 0, defaults).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{Context, Result, bail};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::mp4box::{
+    SampleRun, TrackDefaults, child, child_header, child_path, parse_moof, read_box_header,
+    read_payload, track_defaults, type_name,
+};
+use crate::rewrite::rewrite_file;
+
+const STREAMINFO_LEN: usize = 34;
+
+/// A FLAC metadata block as (block type, data)
+type MetadataBlock = (u8, Vec<u8>);
+
+/// Replaces a fragmented MP4 file holding FLAC audio with a native FLAC stream
+pub fn fmp4_file_to_flac(path: &Path) -> Result<()> {
+    rewrite_file(path, "remux", fmp4_to_flac)
+}
+
+/// Extracts the FLAC stream from fragmented MP4
+///
+/// The metadata blocks come from the `dfLa` box of the `fLaC` sample entry and every sample
+/// is one FLAC frame. STREAMINFO often leaves the total sample count unset in fragmented
+/// files, so it is filled in from the fragment sample durations.
+pub fn fmp4_to_flac<R: Read, W: Write + Seek>(reader: &mut R, writer: &mut W) -> Result<()> {
+    let mut position = 0u64;
+    let mut streaminfo: Option<[u8; STREAMINFO_LEN]> = None;
+    let mut defaults = TrackDefaults::default();
+    let mut runs: Vec<SampleRun> = Vec::new();
+    // None once a sample without a known duration shows up
+    let mut total_samples = Some(0u64);
+
+    while let Some(header) = read_box_header(reader)? {
+        let size = header.size;
+
+        match &header.box_type {
+            b"moov" => {
+                let payload = read_payload(reader, &header)?;
+                let (blocks, trex) = parse_moov(&payload)?;
+                defaults = trex;
+
+                let mut info = [0u8; STREAMINFO_LEN];
+                info.copy_from_slice(&blocks[0].1);
+                streaminfo = Some(info);
+
+                writer.write_all(b"fLaC")?;
+                for (i, (block_type, data)) in blocks.iter().enumerate() {
+                    let last_flag = if i == blocks.len() - 1 { 0x80 } else { 0 };
+                    let length = (data.len() as u32).to_be_bytes();
+                    writer.write_all(&[block_type | last_flag, length[1], length[2], length[3]])?;
+                    writer.write_all(data)?;
+                }
+            }
+            b"moof" => {
+                let payload = read_payload(reader, &header)?;
+                let (fragment_runs, duration) = parse_moof(&payload, position, defaults)?;
+                runs.extend(fragment_runs);
+                total_samples = total_samples.zip(duration).map(|(a, b)| a + b);
+            }
+            b"mdat" => {
+                if streaminfo.is_none() {
+                    bail!("Media data before the FLAC sample description");
+                }
+
+                let data_end = size.map(|s| position + s);
+                let mut cursor = position + header.header_len;
+
+                for run in runs.drain(..) {
+                    if run.offset < cursor {
+                        bail!("Samples out of order in fragmented MP4");
+                    }
+                    skip(reader, run.offset - cursor)?;
+                    cursor = run.offset;
+
+                    for sample_size in run.sizes {
+                        let sample_size = sample_size as u64;
+                        if data_end.is_some_and(|end| cursor + sample_size > end) {
+                            bail!("Sample runs past the end of its media data");
+                        }
+                        let copied = std::io::copy(&mut reader.take(sample_size), writer)
+                            .context("Failed to copy FLAC frame")?;
+                        if copied != sample_size {
+                            bail!("Truncated FLAC frame");
+                        }
+                        cursor += sample_size;
+                    }
+                }
+
+                match data_end {
+                    Some(end) => skip(reader, end - cursor)?,
+                    None => break,
+                }
+            }
+            _ => match size {
+                Some(size) => skip(reader, size - header.header_len)?,
+                None => break,
+            },
+        }
+
+        match size {
+            Some(size) => position += size,
+            None => break,
+        }
+    }
+
+    let Some(mut info) = streaminfo else {
+        bail!("No FLAC sample description found");
+    };
+    if !runs.is_empty() {
+        bail!("Fragment without media data");
+    }
+
+    // fill in the total sample count (36 bits) if the encoder left it unknown
+    let current = ((info[13] & 0x0f) as u64) << 32
+        | u32::from_be_bytes([info[14], info[15], info[16], info[17]]) as u64;
+    if current == 0
+        && let Some(total) = total_samples.filter(|&t| t > 0 && t < 1 << 36)
+    {
+        info[13] = (info[13] & 0xf0) | (total >> 32) as u8;
+        info[14..18].copy_from_slice(&(total as u32).to_be_bytes());
+
+        // STREAMINFO data follows the marker and its block header
+        writer.seek(SeekFrom::Start(8))?;
+        writer.write_all(&info)?;
+        writer.seek(SeekFrom::End(0))?;
+    }
+
+    Ok(())
+}
+
+/// Reads the FLAC metadata blocks and track defaults from a `moov` payload
+fn parse_moov(moov: &[u8]) -> Result<(Vec<MetadataBlock>, TrackDefaults)> {
+    let stsd = child_path(moov, &[b"trak", b"mdia", b"minf", b"stbl", b"stsd"])?
+        .context("No sample description in MP4")?;
+
+    // full box header and entry count, then the first sample entry
+    let entry = stsd.get(8..).context("Truncated 'stsd' box")?;
+    let (entry_type, header_len, entry_size) = child_header(entry)?;
+    if &entry_type != b"fLaC" {
+        bail!(
+            "MP4 holds '{}' audio instead of FLAC",
+            type_name(&entry_type)
+        );
+    }
+
+    // the audio sample entry fields take 28 bytes before its child boxes
+    let entry_children = entry
+        .get(header_len + 28..entry_size)
+        .context("Truncated 'fLaC' sample entry")?;
+    let dfla = child(entry_children, b"dfLa")?.context("No 'dfLa' box in FLAC sample entry")?;
+
+    let mut blocks = Vec::new();
+    let mut pos = 4; // version and flags
+    while pos < dfla.len() {
+        let header = dfla
+            .get(pos..pos + 4)
+            .context("Truncated FLAC metadata block")?;
+        let is_last = header[0] & 0x80 != 0;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let data = dfla
+            .get(pos + 4..pos + 4 + length)
+            .context("Truncated FLAC metadata block")?;
+        blocks.push((header[0] & 0x7f, data.to_vec()));
+        pos += 4 + length;
+
+        if is_last {
+            break;
+        }
+    }
+
+    if blocks.first().map(|(t, d)| (*t, d.len())) != Some((0, STREAMINFO_LEN)) {
+        bail!("FLAC sample entry does not start with STREAMINFO");
+    }
+
+    Ok((blocks, track_defaults(moov)?))
+}
+
+fn skip<R: Read>(reader: &mut R, len: u64) -> Result<()> {
+    let skipped = std::io::copy(&mut reader.take(len), &mut std::io::sink())?;
+    if skipped != len {
+        bail!("Truncated MP4 file");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4box::{full_box, make_box};
+    use std::io::Cursor;
+
+    fn streaminfo() -> Vec<u8> {
+        let mut info = vec![0u8; STREAMINFO_LEN];
+        info[0..2].copy_from_slice(&4096u16.to_be_bytes());
+        info[2..4].copy_from_slice(&4096u16.to_be_bytes());
+        // 44100 Hz, 2 channels, 16 bits, total samples unknown
+        info[10] = 0x0a;
+        info[11] = 0xc4;
+        info[12] = 0x42;
+        info[13] = 0xf0;
+        info
+    }
+
+    fn init_segment() -> Vec<u8> {
+        let mut dfla_body = vec![0x80, 0, 0, STREAMINFO_LEN as u8];
+        dfla_body.extend_from_slice(&streaminfo());
+        let dfla = full_box(b"dfLa", 0, &dfla_body);
+
+        let mut entry = vec![0u8; 28];
+        entry.extend_from_slice(&dfla);
+        let flac_entry = make_box(b"fLaC", &entry);
+
+        let mut stsd_body = 1u32.to_be_bytes().to_vec();
+        stsd_body.extend_from_slice(&flac_entry);
+        let stsd = full_box(b"stsd", 0, &stsd_body);
+
+        let stbl = make_box(b"stbl", &stsd);
+        let minf = make_box(b"minf", &stbl);
+        let mdia = make_box(b"mdia", &minf);
+        let trak = make_box(b"trak", &mdia);
+
+        // track 1, description 1, default duration 4096, default size 0, flags 0
+        let mut trex_body = Vec::new();
+        for value in [1u32, 1, 4096, 0, 0] {
+            trex_body.extend_from_slice(&value.to_be_bytes());
+        }
+        let mvex = make_box(b"mvex", &full_box(b"trex", 0, &trex_body));
+
+        let mut moov = trak;
+        moov.extend_from_slice(&mvex);
+
+        let mut out = make_box(b"ftyp", b"iso6\0\0\0\0");
+        out.extend_from_slice(&make_box(b"moov", &moov));
+        out
+    }
+
+    fn fragment(frames: &[&[u8]]) -> Vec<u8> {
+        let build = |data_offset: u32| {
+            // default-base-is-moof, data offset and per-sample sizes
+            let mut trun_body = (frames.len() as u32).to_be_bytes().to_vec();
+            trun_body.extend_from_slice(&data_offset.to_be_bytes());
+            for frame in frames {
+                trun_body.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            }
+            let mut traf = full_box(b"tfhd", 0x020000, &1u32.to_be_bytes());
+            traf.extend_from_slice(&full_box(b"trun", 0x201, &trun_body));
+
+            let mut moof = full_box(b"mfhd", 0, &1u32.to_be_bytes());
+            moof.extend_from_slice(&make_box(b"traf", &traf));
+            make_box(b"moof", &moof)
+        };
+
+        // samples start right after the moof and the mdat header
+        let moof_len = build(0).len() as u32;
+        let mut out = build(moof_len + 8);
+        out.extend_from_slice(&make_box(b"mdat", &frames.concat()));
+        out
+    }
+
+    #[test]
+    fn test_fmp4_to_flac() {
+        let mut input = init_segment();
+        input.extend_from_slice(&fragment(&[b"frame1", b"f2"]));
+        input.extend_from_slice(&fragment(&[b"frame3"]));
+
+        let mut output = Cursor::new(Vec::new());
+        fmp4_to_flac(&mut Cursor::new(input), &mut output).unwrap();
+        let output = output.into_inner();
+
+        assert_eq!(&output[..4], b"fLaC");
+        assert_eq!(output[4], 0x80); // last metadata block, STREAMINFO
+        let info = &output[8..8 + STREAMINFO_LEN];
+        assert_eq!(&info[..13], &streaminfo()[..13]);
+        // 3 frames of 4096 samples
+        assert_eq!(info[13] & 0x0f, 0);
+        assert_eq!(
+            u32::from_be_bytes([info[14], info[15], info[16], info[17]]),
+            3 * 4096
+        );
+        assert_eq!(&output[8 + STREAMINFO_LEN..], b"frame1f2frame3");
+    }
+
+    #[test]
+    fn test_rejects_other_codecs() {
+        let mut input = init_segment();
+        let at = input.windows(4).position(|w| w == b"fLaC").unwrap();
+        input[at..at + 4].copy_from_slice(b"mp4a");
+        input.extend_from_slice(&fragment(&[b"frame1"]));
+
+        let mut output = Cursor::new(Vec::new());
+        let err = fmp4_to_flac(&mut Cursor::new(input), &mut output).unwrap_err();
+        assert!(err.to_string().contains("'mp4a'"));
+    }
+}
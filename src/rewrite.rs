@@ -0,0 +1,78 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Streams a rewritten copy of a file into a temporary sibling and swaps it in
+///
+/// The sibling is named after the file with `suffix` appended, and is removed again if the
+/// rewrite fails.
+pub fn rewrite_file<F>(path: &Path, suffix: &str, rewrite: F) -> Result<()>
+where
+    F: FnOnce(&mut BufReader<File>, &mut BufWriter<File>) -> Result<()>,
+{
+    let tmp_path = temp_path(path, suffix);
+
+    let result = (|| {
+        let mut reader = BufReader::new(File::open(path).context("Failed to open file")?);
+        let mut writer =
+            BufWriter::new(File::create(&tmp_path).context("Failed to create temporary file")?);
+        rewrite(&mut reader, &mut writer)?;
+        writer.flush().context("Failed to write temporary file")?;
+        // the original is only replaced by a copy that is fully on disk
+        writer
+            .get_ref()
+            .sync_all()
+            .context("Failed to sync temporary file")?;
+        Ok(())
+    })();
+
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    std::fs::rename(&tmp_path, path).context("Failed to replace file with rewritten copy")
+}
+
+/// Temporary sibling path used while a rewritten copy of the file is written
+fn temp_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(suffix);
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_rewrite_file() {
+        let dir = std::env::temp_dir().join(format!("yadal-rewrite-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("01 - Intro.flac");
+        std::fs::write(&path, b"old").unwrap();
+
+        rewrite_file(&path, "tagging", |reader, writer| {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            writer.write_all(&data.to_ascii_uppercase())?;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"OLD");
+
+        // a failed rewrite keeps the file and leaves no temporary sibling behind
+        let result = rewrite_file(&path, "tagging", |_, writer| {
+            writer.write_all(b"partial")?;
+            anyhow::bail!("Broken file")
+        });
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"OLD");
+        assert!(!temp_path(&path, "tagging").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
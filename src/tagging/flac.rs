@@ -0,0 +1,271 @@
+use anyhow::{Context, Result, bail};
+use std::io::{Read, Write};
+
+use crate::cover::Cover;
+use crate::metadata::TrackMetadata;
+
+const STREAMINFO: u8 = 0;
+const PADDING: u8 = 1;
+const VORBIS_COMMENT: u8 = 4;
+const PICTURE: u8 = 6;
+
+const VENDOR: &str = concat!("yadal ", env!("CARGO_PKG_VERSION"));
+
+struct MetadataBlock {
+    block_type: u8,
+    data: Vec<u8>,
+}
+
+/// Copies a FLAC stream from `reader` to `writer` with new metadata blocks
+pub fn rewrite<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    metadata: &TrackMetadata,
+) -> Result<()> {
+    let mut blocks = read_blocks(reader)?;
+
+    // drop old tags and padding, the comment block goes right after STREAMINFO
+    blocks.retain(|b| b.block_type != VORBIS_COMMENT && b.block_type != PADDING);
+    blocks.insert(
+        1,
+        MetadataBlock {
+            block_type: VORBIS_COMMENT,
+            data: vorbis_comment(metadata),
+        },
+    );
+
+    if let Some(cover) = &metadata.cover {
+        blocks.retain(|b| b.block_type != PICTURE);
+        blocks.insert(
+            2,
+            MetadataBlock {
+                block_type: PICTURE,
+                data: picture(cover),
+            },
+        );
+    }
+
+    writer.write_all(b"fLaC")?;
+    write_blocks(writer, &blocks)?;
+    std::io::copy(reader, writer).context("Failed to copy audio frames")?;
+
+    Ok(())
+}
+
+/// Reads a Vorbis comment of a FLAC stream, matching its name case-insensitively
+pub fn read_comment<R: Read>(reader: &mut R, name: &str) -> Result<Option<String>> {
+    let blocks = read_blocks(reader)?;
+    let Some(block) = blocks.iter().find(|b| b.block_type == VORBIS_COMMENT) else {
+        return Ok(None);
+    };
+
+    let data = &block.data;
+    let le_u32 = |at: usize| -> Result<usize> {
+        let bytes = data.get(at..at + 4).context("Truncated Vorbis comment")?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+
+    let mut pos = 4 + le_u32(0)?; // vendor string
+    let count = le_u32(pos)?;
+    pos += 4;
+    for _ in 0..count {
+        let len = le_u32(pos)?;
+        let entry = data
+            .get(pos + 4..pos + 4 + len)
+            .context("Truncated Vorbis comment")?;
+        pos += 4 + len;
+
+        let entry = String::from_utf8_lossy(entry);
+        if let Some((key, value)) = entry.split_once('=')
+            && key.eq_ignore_ascii_case(name)
+        {
+            return Ok(Some(value.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+fn read_blocks<R: Read>(reader: &mut R) -> Result<Vec<MetadataBlock>> {
+    let mut marker = [0u8; 4];
+    reader
+        .read_exact(&mut marker)
+        .context("Failed to read FLAC marker")?;
+    if &marker != b"fLaC" {
+        bail!("Not a FLAC stream");
+    }
+
+    let mut blocks = Vec::new();
+    loop {
+        let mut header = [0u8; 4];
+        reader
+            .read_exact(&mut header)
+            .context("Failed to read metadata block header")?;
+
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+        let mut data = vec![0u8; length];
+        reader
+            .read_exact(&mut data)
+            .context("Failed to read metadata block")?;
+        blocks.push(MetadataBlock { block_type, data });
+
+        if is_last {
+            break;
+        }
+    }
+
+    if blocks.first().map(|b| b.block_type) != Some(STREAMINFO) {
+        bail!("FLAC stream does not start with STREAMINFO");
+    }
+
+    Ok(blocks)
+}
+
+fn write_blocks<W: Write>(writer: &mut W, blocks: &[MetadataBlock]) -> Result<()> {
+    for (i, block) in blocks.iter().enumerate() {
+        let length = block.data.len();
+        if length >= 1 << 24 {
+            bail!("Metadata block too large ({} bytes)", length);
+        }
+
+        let last_flag = if i == blocks.len() - 1 { 0x80 } else { 0 };
+        let length = (length as u32).to_be_bytes();
+        writer.write_all(&[
+            block.block_type | last_flag,
+            length[1],
+            length[2],
+            length[3],
+        ])?;
+        writer.write_all(&block.data)?;
+    }
+    Ok(())
+}
+
+fn vorbis_comment(metadata: &TrackMetadata) -> Vec<u8> {
+    let mut comments: Vec<(&str, String)> = vec![("TITLE", metadata.title.clone())];
+    for artist in &metadata.artists {
+        comments.push(("ARTIST", artist.clone()));
+    }
+    comments.push(("ALBUM", metadata.album.clone()));
+    if let Some(album_artist) = &metadata.album_artist {
+        comments.push(("ALBUMARTIST", album_artist.clone()));
+    }
+    comments.push(("TRACKNUMBER", metadata.track_number.to_string()));
+    if let Some(total) = metadata.track_total {
+        comments.push(("TRACKTOTAL", total.to_string()));
+    }
+    comments.push(("DISCNUMBER", metadata.disc_number.to_string()));
+    if let Some(total) = metadata.disc_total {
+        comments.push(("DISCTOTAL", total.to_string()));
+    }
+    if let Some(date) = &metadata.date {
+        comments.push(("DATE", date.clone()));
+    }
+    if let Some(isrc) = &metadata.isrc {
+        comments.push(("ISRC", isrc.clone()));
+    }
+    if let Some(copyright) = &metadata.copyright {
+        comments.push(("COPYRIGHT", copyright.clone()));
+    }
+    comments.push(("TIDAL_TRACK_ID", metadata.tidal_track_id.to_string()));
+    comments.push(("TIDAL_ALBUM_ID", metadata.tidal_album_id.to_string()));
+
+    // Vorbis comment lengths are little-endian, unlike the rest of FLAC
+    let mut data = Vec::new();
+    data.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+    data.extend_from_slice(VENDOR.as_bytes());
+    data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for (key, value) in comments {
+        let entry = format!("{}={}", key, value);
+        data.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+        data.extend_from_slice(entry.as_bytes());
+    }
+    data
+}
+
+fn picture(cover: &Cover) -> Vec<u8> {
+    let mut data = Vec::with_capacity(cover.data.len() + 64);
+    data.extend_from_slice(&3u32.to_be_bytes()); // front cover
+    data.extend_from_slice(&(cover.mime_type.len() as u32).to_be_bytes());
+    data.extend_from_slice(cover.mime_type.as_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes()); // empty description
+    data.extend_from_slice(&cover.width.to_be_bytes());
+    data.extend_from_slice(&cover.height.to_be_bytes());
+    data.extend_from_slice(&24u32.to_be_bytes()); // color depth
+    data.extend_from_slice(&0u32.to_be_bytes()); // not indexed
+    data.extend_from_slice(&(cover.data.len() as u32).to_be_bytes());
+    data.extend_from_slice(&cover.data);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_flac(extra_blocks: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut blocks = vec![MetadataBlock {
+            block_type: STREAMINFO,
+            data: vec![0xAB; 34],
+        }];
+        for (block_type, data) in extra_blocks {
+            blocks.push(MetadataBlock {
+                block_type: *block_type,
+                data: data.to_vec(),
+            });
+        }
+
+        let mut out = b"fLaC".to_vec();
+        write_blocks(&mut out, &blocks).unwrap();
+        out.extend_from_slice(b"audio frames");
+        out
+    }
+
+    #[test]
+    fn test_rewrite_replaces_comments_and_keeps_audio() {
+        let input = sample_flac(&[(VORBIS_COMMENT, b"old"), (PADDING, &[0; 16])]);
+        let metadata = TrackMetadata {
+            title: "Song".to_string(),
+            artists: vec!["A".to_string(), "B".to_string()],
+            track_number: 3,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        rewrite(&mut input.as_slice(), &mut output, &metadata).unwrap();
+
+        let mut reader = output.as_slice();
+        let blocks = read_blocks(&mut reader).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].data, vec![0xAB; 34]);
+        assert_eq!(blocks[1].block_type, VORBIS_COMMENT);
+        assert_eq!(blocks[1].data, vorbis_comment(&metadata));
+        assert_eq!(reader, b"audio frames");
+    }
+
+    #[test]
+    fn test_read_comment() {
+        let metadata = TrackMetadata {
+            tidal_track_id: 437468401,
+            ..Default::default()
+        };
+        let input = sample_flac(&[(VORBIS_COMMENT, &vorbis_comment(&metadata))]);
+
+        let read = |name| read_comment(&mut input.as_slice(), name).unwrap();
+        assert_eq!(read("tidal_track_id").as_deref(), Some("437468401"));
+        assert_eq!(read("LYRICS"), None);
+    }
+
+    #[test]
+    fn test_vorbis_comment_lists_every_artist() {
+        let metadata = TrackMetadata {
+            artists: vec!["A".to_string(), "B".to_string()],
+            ..Default::default()
+        };
+        let data = vorbis_comment(&metadata);
+        let text = String::from_utf8_lossy(&data);
+        assert!(text.contains("ARTIST=A"));
+        assert!(text.contains("ARTIST=B"));
+    }
+}
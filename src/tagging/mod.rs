@@ -0,0 +1,50 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::metadata::TrackMetadata;
+use crate::rewrite::rewrite_file;
+
+mod flac;
+mod mp4;
+
+/// Writes tags into a downloaded file, detecting the container from its header
+pub fn write_tags(path: &Path, metadata: &TrackMetadata) -> Result<()> {
+    let mut magic = [0u8; 8];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .context("Failed to read file header")?;
+
+    if magic.starts_with(b"fLaC") {
+        rewrite_file(path, "tagging", |reader, writer| {
+            flac::rewrite(reader, writer, metadata)
+        })
+    } else if &magic[4..8] == b"ftyp" {
+        rewrite_file(path, "tagging", |reader, writer| {
+            mp4::rewrite(reader, writer, metadata)
+        })
+    } else {
+        // other containers are left untouched
+        Ok(())
+    }
+}
+
+/// Reads the TIDAL track ID yadal tagged a file with, if it has one
+pub fn read_track_id(path: &Path) -> Result<Option<u64>> {
+    let mut reader = BufReader::new(File::open(path).context("Failed to open file")?);
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .context("Failed to read file header")?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let value = if magic.starts_with(b"fLaC") {
+        flac::read_comment(&mut reader, "TIDAL_TRACK_ID")?
+    } else if &magic[4..8] == b"ftyp" {
+        mp4::read_freeform(&mut reader, "TIDAL_TRACK_ID")?
+    } else {
+        None
+    };
+    Ok(value.and_then(|id| id.trim().parse().ok()))
+}
@@ -0,0 +1,402 @@
+use anyhow::{Context, Result, bail};
+use std::io::{Read, Write};
+
+use crate::metadata::TrackMetadata;
+use crate::mp4box::{
+    child, child_header, child_path, children, make_box, read_box_header, read_payload, read_u32,
+    read_u64, type_name,
+};
+
+/// Boxes whose payload is made up entirely of child boxes
+const CONTAINERS: [&[u8; 4]; 12] = [
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"udta", b"edts", b"dinf", b"mvex", b"moof",
+    b"traf", b"mfra",
+];
+
+/// Copies an MP4 stream from `reader` to `writer` with an iTunes-style `ilst` in `moov/udta/meta`
+///
+/// Works for both regular and fragmented MP4. When `moov` grows, every absolute file
+/// offset behind it (`stco`/`co64`, `tfhd` base offsets, `tfra` entries) is shifted.
+pub fn rewrite<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    metadata: &TrackMetadata,
+) -> Result<()> {
+    let meta = build_meta(metadata);
+
+    let mut position = 0u64;
+    let mut shift: Option<(u64, i64)> = None; // (old moov offset, size delta)
+    let mut seen_moov = false;
+
+    while let Some(header) = read_box_header(reader)? {
+        let Some(size) = header.size else {
+            // box extends to the end of the file, nothing follows it
+            writer.write_all(&header.raw)?;
+            std::io::copy(reader, writer).context("Failed to copy box data")?;
+            break;
+        };
+
+        match (&header.box_type, shift) {
+            (b"moov", _) => {
+                let payload = read_payload(reader, &header)?;
+                let mut moov = make_box(b"moov", &rebuild_moov(&payload, &meta)?);
+
+                let delta = moov.len() as i64 - size as i64;
+                shift = Some((position, delta));
+                patch_offsets(&mut moov[8..], position, delta)?;
+
+                writer.write_all(&moov)?;
+                seen_moov = true;
+            }
+            (b"moof" | b"mfra", Some((moov_offset, delta))) => {
+                let mut payload = read_payload(reader, &header)?;
+                patch_offsets(&mut payload, moov_offset, delta)?;
+
+                writer.write_all(&header.raw)?;
+                writer.write_all(&payload)?;
+            }
+            _ => {
+                writer.write_all(&header.raw)?;
+                let copied = std::io::copy(&mut reader.take(size - header.header_len), writer)
+                    .context("Failed to copy box data")?;
+                if copied != size - header.header_len {
+                    bail!("Truncated '{}' box", type_name(&header.box_type));
+                }
+            }
+        }
+
+        position += size;
+    }
+
+    if !seen_moov {
+        bail!("No moov box found");
+    }
+
+    Ok(())
+}
+
+/// Reads a freeform iTunes item (`----`) from the `moov/udta/meta/ilst` of an MP4 stream
+pub fn read_freeform<R: Read>(reader: &mut R, name: &str) -> Result<Option<String>> {
+    while let Some(header) = read_box_header(reader)? {
+        if &header.box_type != b"moov" {
+            let Some(size) = header.size else {
+                break;
+            };
+            let len = size - header.header_len;
+            if std::io::copy(&mut reader.take(len), &mut std::io::sink())? != len {
+                bail!("Truncated '{}' box", type_name(&header.box_type));
+            }
+            continue;
+        }
+
+        let moov = read_payload(reader, &header)?;
+        // the meta box is a full box, its children follow version and flags
+        let Some(meta) = child_path(&moov, &[b"udta", b"meta"])? else {
+            return Ok(None);
+        };
+        let Some(ilst) = child(meta.get(4..).unwrap_or_default(), b"ilst")? else {
+            return Ok(None);
+        };
+
+        for (item_type, item) in children(ilst)? {
+            if &item_type != b"----" {
+                continue;
+            }
+            let (_, header_len, _) = child_header(item)?;
+            let item = &item[header_len..];
+            // name and data payloads start with version and flags, data also with a locale
+            let item_name = child(item, b"name")?.and_then(|n| n.get(4..));
+            if item_name == Some(name.as_bytes())
+                && let Some(value) = child(item, b"data")?.and_then(|d| d.get(8..))
+            {
+                return Ok(Some(String::from_utf8_lossy(value).into_owned()));
+            }
+        }
+        return Ok(None);
+    }
+    Ok(None)
+}
+
+/// Rebuilds the `moov` payload with `meta` replacing any existing one in `udta`
+fn rebuild_moov(payload: &[u8], meta: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(payload.len() + meta.len() + 8);
+    let mut has_udta = false;
+
+    for (box_type, data) in children(payload)? {
+        if &box_type != b"udta" {
+            out.extend_from_slice(data);
+            continue;
+        }
+
+        let (_, header_len, _) = child_header(data)?;
+        let mut udta = Vec::new();
+        for (child_type, child) in children(&data[header_len..])? {
+            if &child_type != b"meta" {
+                udta.extend_from_slice(child);
+            }
+        }
+        udta.extend_from_slice(meta);
+        out.extend_from_slice(&make_box(b"udta", &udta));
+        has_udta = true;
+    }
+
+    if !has_udta {
+        out.extend_from_slice(&make_box(b"udta", meta));
+    }
+
+    Ok(out)
+}
+
+/// Shifts absolute file offsets that point past `moov_offset` by `delta`
+fn patch_offsets(buf: &mut [u8], moov_offset: u64, delta: i64) -> Result<()> {
+    if delta == 0 {
+        return Ok(());
+    }
+
+    let shift = |offset: u64| -> Result<u64> {
+        if offset < moov_offset {
+            return Ok(offset);
+        }
+        offset
+            .checked_add_signed(delta)
+            .context("File offset out of range")
+    };
+
+    let mut pos = 0;
+    while pos < buf.len() {
+        let (box_type, header_len, size) = child_header(&buf[pos..])?;
+        let body = &mut buf[pos + header_len..pos + size];
+
+        if CONTAINERS.contains(&&box_type) {
+            patch_offsets(body, moov_offset, delta)?;
+        } else if &box_type == b"stco" {
+            for i in 0..entry_count(body, 4)? {
+                let at = 8 + i * 4;
+                let offset = shift(read_u32(body, at) as u64)?;
+                let offset = u32::try_from(offset).context("Chunk offset exceeds 32 bits")?;
+                body[at..at + 4].copy_from_slice(&offset.to_be_bytes());
+            }
+        } else if &box_type == b"co64" {
+            for i in 0..entry_count(body, 8)? {
+                let at = 8 + i * 8;
+                let offset = shift(read_u64(body, at))?;
+                body[at..at + 8].copy_from_slice(&offset.to_be_bytes());
+            }
+        } else if &box_type == b"tfhd" && body.len() >= 16 && body[3] & 0x01 != 0 {
+            // base-data-offset-present
+            let offset = shift(read_u64(body, 8))?;
+            body[8..16].copy_from_slice(&offset.to_be_bytes());
+        } else if &box_type == b"tfra" && body.len() >= 16 {
+            let version = body[0];
+            let lengths = read_u32(body, 8);
+            let field_len = if version == 1 { 8 } else { 4 };
+            let entry_len = 2 * field_len
+                + ((lengths >> 4) & 3) as usize
+                + ((lengths >> 2) & 3) as usize
+                + (lengths & 3) as usize
+                + 3;
+
+            for i in 0..read_u32(body, 12) as usize {
+                let at = 16 + i * entry_len + field_len;
+                if at + field_len > body.len() {
+                    bail!("Truncated tfra box");
+                }
+                if version == 1 {
+                    let offset = shift(read_u64(body, at))?;
+                    body[at..at + 8].copy_from_slice(&offset.to_be_bytes());
+                } else {
+                    let offset = shift(read_u32(body, at) as u64)?;
+                    let offset =
+                        u32::try_from(offset).context("Fragment offset exceeds 32 bits")?;
+                    body[at..at + 4].copy_from_slice(&offset.to_be_bytes());
+                }
+            }
+        }
+
+        pos += size;
+    }
+
+    Ok(())
+}
+
+/// Number of entries in a `stco`/`co64` box, checked against the box size
+fn entry_count(body: &[u8], entry_len: usize) -> Result<usize> {
+    if body.len() < 8 {
+        bail!("Truncated chunk offset box");
+    }
+    let count = read_u32(body, 4) as usize;
+    if body.len() < 8 + count * entry_len {
+        bail!("Truncated chunk offset box");
+    }
+    Ok(count)
+}
+
+fn build_meta(metadata: &TrackMetadata) -> Vec<u8> {
+    let mut ilst = Vec::new();
+    ilst.extend(text_item(b"\xa9nam", &metadata.title));
+    ilst.extend(text_item(b"\xa9ART", &metadata.artists.join(", ")));
+    ilst.extend(text_item(b"\xa9alb", &metadata.album));
+    if let Some(album_artist) = &metadata.album_artist {
+        ilst.extend(text_item(b"aART", album_artist));
+    }
+    ilst.extend(number_pair_item(
+        b"trkn",
+        metadata.track_number,
+        metadata.track_total.unwrap_or(0),
+    ));
+    ilst.extend(number_pair_item(
+        b"disk",
+        metadata.disc_number,
+        metadata.disc_total.unwrap_or(0),
+    ));
+    if let Some(date) = &metadata.date {
+        ilst.extend(text_item(b"\xa9day", date));
+    }
+    if let Some(copyright) = &metadata.copyright {
+        ilst.extend(text_item(b"cprt", copyright));
+    }
+    if let Some(cover) = &metadata.cover {
+        let type_indicator = match cover.mime_type {
+            "image/png" => 14,
+            _ => 13,
+        };
+        ilst.extend(make_box(b"covr", &data_box(type_indicator, &cover.data)));
+    }
+    if let Some(isrc) = &metadata.isrc {
+        ilst.extend(freeform_item("ISRC", isrc));
+    }
+    ilst.extend(freeform_item(
+        "TIDAL_TRACK_ID",
+        &metadata.tidal_track_id.to_string(),
+    ));
+    ilst.extend(freeform_item(
+        "TIDAL_ALBUM_ID",
+        &metadata.tidal_album_id.to_string(),
+    ));
+
+    // handler box marking this as iTunes metadata
+    let mut hdlr = vec![0u8; 8];
+    hdlr.extend_from_slice(b"mdirappl");
+    hdlr.extend_from_slice(&[0u8; 9]);
+
+    let mut meta = vec![0u8; 4]; // version and flags
+    meta.extend(make_box(b"hdlr", &hdlr));
+    meta.extend(make_box(b"ilst", &ilst));
+    make_box(b"meta", &meta)
+}
+
+fn data_box(type_indicator: u32, value: &[u8]) -> Vec<u8> {
+    let mut payload = type_indicator.to_be_bytes().to_vec();
+    payload.extend_from_slice(&[0u8; 4]); // locale
+    payload.extend_from_slice(value);
+    make_box(b"data", &payload)
+}
+
+fn text_item(name: &[u8; 4], value: &str) -> Vec<u8> {
+    make_box(name, &data_box(1, value.as_bytes()))
+}
+
+fn number_pair_item(name: &[u8; 4], number: u32, total: u32) -> Vec<u8> {
+    let mut value = vec![0u8; 2];
+    value.extend_from_slice(&(number.min(u16::MAX as u32) as u16).to_be_bytes());
+    value.extend_from_slice(&(total.min(u16::MAX as u32) as u16).to_be_bytes());
+    value.extend_from_slice(&[0u8; 2]);
+    make_box(name, &data_box(0, &value))
+}
+
+fn freeform_item(name: &str, value: &str) -> Vec<u8> {
+    let mut mean = vec![0u8; 4];
+    mean.extend_from_slice(b"com.apple.iTunes");
+    let mut name_payload = vec![0u8; 4];
+    name_payload.extend_from_slice(name.as_bytes());
+
+    let mut payload = make_box(b"mean", &mean);
+    payload.extend(make_box(b"name", &name_payload));
+    payload.extend(data_box(1, value.as_bytes()));
+    make_box(b"----", &payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4box::full_box;
+
+    fn moov_with_chunk_offset(offset: u32) -> Vec<u8> {
+        let mut stco = 1u32.to_be_bytes().to_vec();
+        stco.extend_from_slice(&offset.to_be_bytes());
+        let stbl = make_box(b"stbl", &full_box(b"stco", 0, &stco));
+        let trak = make_box(b"trak", &make_box(b"mdia", &make_box(b"minf", &stbl)));
+        make_box(b"moov", &trak)
+    }
+
+    #[test]
+    fn test_rewrite_shifts_chunk_offsets() {
+        let ftyp = make_box(b"ftyp", b"M4A \0\0\0\0");
+
+        // chunk starts right after the mdat header
+        let moov_len = moov_with_chunk_offset(0).len();
+        let moov = moov_with_chunk_offset((ftyp.len() + moov_len + 8) as u32);
+
+        let mut input = ftyp.clone();
+        input.extend(moov);
+        input.extend(make_box(b"mdat", b"samples"));
+
+        let metadata = TrackMetadata {
+            title: "Song".to_string(),
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        rewrite(&mut input.as_slice(), &mut output, &metadata).unwrap();
+
+        let boxes = children(&output).unwrap();
+        assert_eq!(boxes.len(), 3);
+        assert_eq!(&boxes[1].0, b"moov");
+        assert!(boxes[1].1.windows(4).any(|w| w == b"ilst"));
+
+        // the shifted offset must still point at the sample data
+        let stco_at = boxes[1].1.windows(4).position(|w| w == b"stco").unwrap() + 4;
+        let offset = read_u32(boxes[1].1, stco_at + 8) as usize;
+        assert_eq!(&output[offset..offset + 7], b"samples");
+    }
+
+    #[test]
+    fn test_read_freeform() {
+        let mut input = make_box(b"ftyp", b"M4A \0\0\0\0");
+        input.extend(moov_with_chunk_offset(0));
+        let metadata = TrackMetadata {
+            tidal_track_id: 437468401,
+            ..Default::default()
+        };
+        let mut tagged = Vec::new();
+        rewrite(&mut input.as_slice(), &mut tagged, &metadata).unwrap();
+        tagged.extend(make_box(b"mdat", b"samples"));
+
+        let read = |input: &[u8], name| read_freeform(&mut &input[..], name).unwrap();
+        assert_eq!(
+            read(&tagged, "TIDAL_TRACK_ID").as_deref(),
+            Some("437468401")
+        );
+        assert_eq!(read(&tagged, "LYRICS"), None);
+        assert_eq!(read(&input, "TIDAL_TRACK_ID"), None);
+    }
+
+    #[test]
+    fn test_rewrite_replaces_existing_meta() {
+        let metadata = TrackMetadata::default();
+        let old_meta = make_box(b"meta", b"old");
+        let moov = make_box(b"moov", &make_box(b"udta", &old_meta));
+
+        let mut input = make_box(b"ftyp", b"M4A ");
+        input.extend(moov);
+
+        let mut output = Vec::new();
+        rewrite(&mut input.as_slice(), &mut output, &metadata).unwrap();
+
+        let boxes = children(&output).unwrap();
+        let moov = boxes[1].1;
+        let udta = children(&moov[8..]).unwrap();
+        let metas = children(&udta[0].1[8..]).unwrap();
+        assert_eq!(metas.len(), 1);
+        assert_eq!(metas[0].1, build_meta(&metadata).as_slice());
+    }
+}
@@ -0,0 +1,338 @@
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Placeholders available in album directory templates
+pub const ALBUM_FIELDS: &[&str] = &[
+    "album_artist",
+    "artist",
+    "album",
+    "year",
+    "version",
+    "explicit",
+    "tidal_id",
+];
+
+/// Placeholders available in playlist directory templates
+pub const PLAYLIST_FIELDS: &[&str] = &["playlist", "tidal_id", "date"];
+
+/// Placeholders available in mix directory templates
+pub const MIX_FIELDS: &[&str] = &["mix", "tidal_id", "date"];
+
+/// Placeholders available in track file templates
+pub const TRACK_FIELDS: &[&str] = &[
+    "album_artist",
+    "artist",
+    "album",
+    "year",
+    "disc",
+    "track",
+    "position",
+    "title",
+    "version",
+    "explicit",
+    "quality",
+    "isrc",
+    "tidal_id",
+];
+
+pub type TemplateValues = HashMap<&'static str, String>;
+
+pub const DEFAULT_ALBUM_TEMPLATE: &str = "{album_artist} - {album}";
+pub const DEFAULT_PLAYLIST_TEMPLATE: &str = "{playlist}-playlist";
+pub const DEFAULT_MIX_TEMPLATE: &str = "{mix}-mix";
+
+/// How tracks of multi-disc albums are kept apart by the default track template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscLayout {
+    /// `CD1/01 - Title`, `CD2/01 - Title`
+    Subfolders,
+    /// `1-01 - Title`, `2-01 - Title`
+    Prefix,
+}
+
+/// Directory and file name templates for a download run
+pub struct Naming {
+    pub album: Template,
+    pub playlist: Template,
+    pub mix: Template,
+    /// User-supplied track template, replaces all of the defaults below
+    pub track: Option<Template>,
+    pub disc_layout: DiscLayout,
+}
+
+impl Naming {
+    /// Template for track files, picking a default that fits the collection
+    pub fn track_template(&self, in_playlist: bool, multi_disc: bool) -> Template {
+        if let Some(template) = &self.track {
+            return template.clone();
+        }
+
+        let default = match (in_playlist, multi_disc, self.disc_layout) {
+            (true, _, _) => "{position:03} - {title}",
+            (false, false, _) => "{track:03} - {title}",
+            (false, true, DiscLayout::Subfolders) => "CD{disc}/{track:02} - {title}",
+            (false, true, DiscLayout::Prefix) => "{disc}-{track:02} - {title}",
+        };
+        Template::parse(default, TRACK_FIELDS).expect("default track template is valid")
+    }
+
+    /// Template for videos downloaded on their own, outside of any album or playlist
+    pub fn video_template(&self) -> Template {
+        match &self.track {
+            Some(template) => template.clone(),
+            None => Template::parse("{artist} - {title}", TRACK_FIELDS)
+                .expect("default video template is valid"),
+        }
+    }
+}
+
+/// A parsed path template such as `{album_artist}/{year} - {album}`
+///
+/// - `{name}` inserts a value, `{name:02}` zero-pads it to two characters
+/// - `<...>` is a conditional section, dropped when any placeholder inside is empty
+/// - `/` separates directories; every component is sanitized on its own
+/// - `{{` and `}}` are literal braces
+#[derive(Debug, Clone)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Field {
+        name: String,
+        width: usize,
+        zero_pad: bool,
+    },
+    Optional(Vec<Segment>),
+}
+
+impl Template {
+    /// Parses a template, rejecting placeholders not listed in `fields`
+    pub fn parse(template: &str, fields: &[&str]) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut optional = Vec::new();
+        let mut in_optional = false;
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            let current = if in_optional {
+                &mut optional
+            } else {
+                &mut segments
+            };
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    flush_literal(&mut literal, current);
+
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => bail!("Unclosed '{{' in template '{}'", template),
+                        }
+                    }
+                    current.push(parse_field(&spec, fields)?);
+                }
+                '}' => bail!("Unmatched '}}' in template '{}'", template),
+                '<' => {
+                    if in_optional {
+                        bail!("Nested '<' sections are not supported in '{}'", template);
+                    }
+                    flush_literal(&mut literal, current);
+                    in_optional = true;
+                }
+                '>' => {
+                    if !in_optional {
+                        bail!("Unmatched '>' in template '{}'", template);
+                    }
+                    flush_literal(&mut literal, current);
+                    segments.push(Segment::Optional(std::mem::take(&mut optional)));
+                    in_optional = false;
+                }
+                c => literal.push(c),
+            }
+        }
+
+        if in_optional {
+            bail!("Unclosed '<' in template '{}'", template);
+        }
+        flush_literal(&mut literal, &mut segments);
+
+        Ok(Self { segments })
+    }
+
+    /// Renders the template into a relative path with every component sanitized
+    pub fn render(&self, values: &TemplateValues) -> PathBuf {
+        let mut rendered = String::new();
+        render_segments(&self.segments, values, &mut rendered);
+
+        rendered
+            .split('/')
+            .map(|component| sanitize_filename::sanitize(component.trim()))
+            .filter(|component| !component.is_empty())
+            .collect()
+    }
+}
+
+/// Today's date (UTC) as `YYYY-MM-DD`, for dated snapshots of mixes and playlists
+pub fn today() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    date_from_unix(secs)
+}
+
+/// Converts a Unix timestamp into a proleptic Gregorian calendar date
+pub fn date_from_unix(secs: u64) -> String {
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn flush_literal(literal: &mut String, segments: &mut Vec<Segment>) {
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(std::mem::take(literal)));
+    }
+}
+
+fn parse_field(spec: &str, fields: &[&str]) -> Result<Segment> {
+    let (name, format) = match spec.split_once(':') {
+        Some((name, format)) => (name.trim(), Some(format.trim())),
+        None => (spec.trim(), None),
+    };
+
+    if !fields.contains(&name) {
+        bail!(
+            "Unknown placeholder '{{{}}}', expected one of: {}",
+            name,
+            fields.join(", ")
+        );
+    }
+
+    let (width, zero_pad) = match format {
+        None => (0, false),
+        Some(format) => match format.parse::<usize>() {
+            Ok(width) => (width, format.starts_with('0')),
+            Err(_) => bail!("Invalid format '{}' for placeholder '{{{}}}'", format, name),
+        },
+    };
+
+    Ok(Segment::Field {
+        name: name.to_string(),
+        width,
+        zero_pad,
+    })
+}
+
+/// Appends the rendered segments to `out`, returns false if any field was empty
+fn render_segments(segments: &[Segment], values: &TemplateValues, out: &mut String) -> bool {
+    let mut complete = true;
+
+    for segment in segments {
+        match segment {
+            Segment::Literal(text) => out.push_str(text),
+            Segment::Field {
+                name,
+                width,
+                zero_pad,
+            } => {
+                // values are sanitized on their own so a '/' in a title never creates a directory
+                let value = values
+                    .get(name.as_str())
+                    .map(sanitize_filename::sanitize)
+                    .unwrap_or_default();
+                if value.is_empty() {
+                    complete = false;
+                    continue;
+                }
+
+                let fill = if *zero_pad { '0' } else { ' ' };
+                for _ in value.chars().count()..*width {
+                    out.push(fill);
+                }
+                out.push_str(&value);
+            }
+            Segment::Optional(section) => {
+                let mut rendered = String::new();
+                if render_segments(section, values, &mut rendered) {
+                    out.push_str(&rendered);
+                }
+            }
+        }
+    }
+
+    complete
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&'static str, &str)]) -> TemplateValues {
+        pairs.iter().map(|(k, v)| (*k, v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_render_directories_and_padding() {
+        let template = Template::parse("{album_artist}/{year} - {album}", ALBUM_FIELDS).unwrap();
+        let path = template.render(&values(&[
+            ("album_artist", "AC/DC"),
+            ("year", "1980"),
+            ("album", "Back in Black"),
+        ]));
+        assert_eq!(path, PathBuf::from("ACDC/1980 - Back in Black"));
+
+        let template = Template::parse("{disc}-{track:02} - {title}", TRACK_FIELDS).unwrap();
+        let path = template.render(&values(&[("disc", "1"), ("track", "7"), ("title", "Song")]));
+        assert_eq!(path, PathBuf::from("1-07 - Song"));
+    }
+
+    #[test]
+    fn test_render_conditional_sections() {
+        let template =
+            Template::parse("{title}< ({version})>< [{explicit}]>", TRACK_FIELDS).unwrap();
+
+        let path = template.render(&values(&[("title", "Song"), ("version", "Live")]));
+        assert_eq!(path, PathBuf::from("Song (Live)"));
+
+        let path = template.render(&values(&[("title", "Song"), ("explicit", "E")]));
+        assert_eq!(path, PathBuf::from("Song [E]"));
+    }
+
+    #[test]
+    fn test_date_from_unix() {
+        assert_eq!(date_from_unix(0), "1970-01-01");
+        assert_eq!(date_from_unix(951_782_400), "2000-02-29");
+        assert_eq!(date_from_unix(1_791_676_800), "2026-10-11");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Template::parse("{nope}", TRACK_FIELDS).is_err());
+        assert!(Template::parse("{title", TRACK_FIELDS).is_err());
+        assert!(Template::parse("<{title}", TRACK_FIELDS).is_err());
+        assert!(Template::parse("{track:xx}", TRACK_FIELDS).is_err());
+        assert!(Template::parse("{playlist}", ALBUM_FIELDS).is_err());
+    }
+}
@@ -1,6 +1,172 @@
-#[derive(Debug, Clone, Copy)]
+use std::fmt;
+use tidlers::client::models::album::Album;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MediaType {
     Track,
     Album,
     Playlist,
+    Artist,
+    Mix,
+    Video,
+}
+
+impl fmt::Display for MediaType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MediaType::Track => "track",
+            MediaType::Album => "album",
+            MediaType::Playlist => "playlist",
+            MediaType::Artist => "artist",
+            MediaType::Mix => "mix",
+            MediaType::Video => "video",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Part of the logged-in user's collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Favorites {
+    Tracks,
+    Albums,
+    Playlists,
+    Artists,
+}
+
+/// Highest video resolution to download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoQuality {
+    MaxHeight(u32),
+    Best,
+}
+
+/// Container that FLAC audio from DASH manifests is saved in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DashContainer {
+    /// Native FLAC stream, remuxed from the fragmented MP4 segments
+    Flac,
+    /// The fragmented MP4 as downloaded
+    M4a,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReleaseType {
+    Album,
+    Ep,
+    Single,
+    /// Compilations and other releases the artist appears on
+    Compilation,
+}
+
+/// Artist release lists as split up by the TIDAL API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlbumList {
+    Albums,
+    EpsAndSingles,
+    Compilations,
+}
+
+/// Selects which releases of an artist's discography get downloaded
+#[derive(Debug, Clone)]
+pub struct ReleaseFilter {
+    pub release_types: Vec<ReleaseType>,
+    /// Inclusive lower bound, `YYYY`, `YYYY-MM` or `YYYY-MM-DD`
+    pub released_after: Option<String>,
+    /// Inclusive upper bound, `YYYY`, `YYYY-MM` or `YYYY-MM-DD`
+    pub released_before: Option<String>,
+}
+
+impl ReleaseFilter {
+    /// Release lists that have to be fetched for the selected release types
+    pub fn album_lists(&self) -> Vec<AlbumList> {
+        let mut lists = Vec::new();
+        for release_type in &self.release_types {
+            let list = match release_type {
+                ReleaseType::Album => AlbumList::Albums,
+                ReleaseType::Ep | ReleaseType::Single => AlbumList::EpsAndSingles,
+                ReleaseType::Compilation => AlbumList::Compilations,
+            };
+            if !lists.contains(&list) {
+                lists.push(list);
+            }
+        }
+        lists
+    }
+
+    /// Whether a release fetched from `list` passes the type and date filters
+    pub fn matches(&self, list: AlbumList, album: &Album) -> bool {
+        let type_matches = match list {
+            // appears-on releases keep whatever type the other artist gave them
+            AlbumList::Compilations => true,
+            _ => {
+                let release_type = match album.album_type.as_str() {
+                    "EP" => ReleaseType::Ep,
+                    "SINGLE" => ReleaseType::Single,
+                    _ => ReleaseType::Album,
+                };
+                self.release_types.contains(&release_type)
+            }
+        };
+
+        type_matches && self.date_matches(album.release_date.as_deref())
+    }
+
+    fn date_matches(&self, date: Option<&str>) -> bool {
+        if self.released_after.is_none() && self.released_before.is_none() {
+            return true;
+        }
+        let Some(date) = date else {
+            return false;
+        };
+
+        // compare only as precisely as the bound was given, so "2020" includes all of 2020
+        let prefix = |bound: &str| date.get(..bound.len()).unwrap_or(date).to_string();
+        let after = self
+            .released_after
+            .as_deref()
+            .is_none_or(|bound| prefix(bound).as_str() >= bound);
+        let before = self
+            .released_before
+            .as_deref()
+            .is_none_or(|bound| prefix(bound).as_str() <= bound);
+        after && before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(after: Option<&str>, before: Option<&str>) -> ReleaseFilter {
+        ReleaseFilter {
+            release_types: vec![ReleaseType::Album],
+            released_after: after.map(str::to_string),
+            released_before: before.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_date_range_is_inclusive_at_given_precision() {
+        let f = filter(Some("2010"), Some("2012-06"));
+        assert!(f.date_matches(Some("2010-01-01")));
+        assert!(f.date_matches(Some("2012-06-30")));
+        assert!(!f.date_matches(Some("2009-12-31")));
+        assert!(!f.date_matches(Some("2012-07-01")));
+        assert!(!f.date_matches(None));
+        assert!(filter(None, None).date_matches(None));
+    }
+
+    #[test]
+    fn test_album_lists_are_deduplicated() {
+        let f = ReleaseFilter {
+            release_types: vec![ReleaseType::Ep, ReleaseType::Single, ReleaseType::Album],
+            released_after: None,
+            released_before: None,
+        };
+        assert_eq!(
+            f.album_lists(),
+            vec![AlbumList::EpsAndSingles, AlbumList::Albums]
+        );
+    }
 }
@@ -0,0 +1,497 @@
+use anyhow::{Context, Result, bail};
+use md5::{Digest, Md5};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::mp4box::{
+    SampleRun, TrackDefaults, be_u32, be_u64, child, child_header, child_path, children,
+    parse_moof, read_box_header, read_payload, track_defaults, type_name,
+};
+
+/// Checks that an audio file is complete and intact, returning its length in seconds
+///
+/// FLAC files are decoded completely (frame CRCs, sample count and the STREAMINFO MD5),
+/// MP4 files have their box structure and sample tables or fragments checked against the
+/// media data they point into.
+pub fn verify_file(path: &Path) -> Result<f64> {
+    let mut reader = BufReader::new(File::open(path).context("Failed to open file")?);
+    let file_len = reader
+        .get_ref()
+        .metadata()
+        .context("Failed to read file size")?
+        .len();
+
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .context("File is too short for an audio file")?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    if magic.starts_with(b"fLaC") {
+        verify_flac(reader)
+    } else if &magic[4..8] == b"ftyp" {
+        verify_mp4(&mut reader, file_len)
+    } else {
+        bail!("Unknown audio container")
+    }
+}
+
+/// Verifies every FLAC and MP4 audio file below `dir`, printing the result of each
+///
+/// Fails if any of the files is damaged.
+pub fn verify_dir(dir: &Path) -> Result<()> {
+    let mut files = Vec::new();
+    collect_audio_files(dir, &mut files)?;
+    files.sort();
+
+    println!("verifying {} files in {}...\n", files.len(), dir.display());
+
+    let mut failed = 0;
+    for path in &files {
+        let name = path.strip_prefix(dir).unwrap_or(path).display();
+        match verify_file(path) {
+            Ok(_) => println!("✓ {}", name),
+            Err(e) => {
+                failed += 1;
+                println!("✗ {} ({:#})", name, e);
+            }
+        }
+    }
+
+    println!("\nsummary:");
+    println!("  verified: {}", files.len() - failed);
+    if failed > 0 {
+        println!("  failed: {}", failed);
+        bail!("{} files failed verification", failed);
+    }
+    Ok(())
+}
+
+pub fn collect_audio_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+
+    for entry in entries {
+        let path = entry.context("Failed to read directory")?.path();
+        if path.is_dir() {
+            collect_audio_files(&path, files)?;
+        } else if matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("flac" | "m4a")
+        ) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn verify_flac<R: Read>(reader: R) -> Result<f64> {
+    let mut flac = claxon::FlacReader::new(reader).context("Invalid FLAC stream")?;
+    let info = flac.streaminfo();
+    if info.sample_rate == 0 {
+        bail!("FLAC stream without a sample rate");
+    }
+
+    // the MD5 covers the samples as interleaved little-endian integers of the stream's width
+    let sample_bytes = info.bits_per_sample.div_ceil(8) as usize;
+    let mut md5 = Md5::new();
+    let mut decoded = 0u64;
+
+    let mut frames = flac.blocks();
+    let mut buffer = Vec::new();
+    let mut bytes = Vec::new();
+    while let Some(block) = frames
+        .read_next_or_eof(buffer)
+        .with_context(|| format!("Corrupt FLAC frame after {} samples", decoded))?
+    {
+        let channels: Vec<&[i32]> = (0..block.channels()).map(|c| block.channel(c)).collect();
+        bytes.clear();
+        for i in 0..block.duration() as usize {
+            for channel in &channels {
+                bytes.extend_from_slice(&channel[i].to_le_bytes()[..sample_bytes]);
+            }
+        }
+        md5.update(&bytes);
+
+        decoded += block.duration() as u64;
+        buffer = block.into_buffer();
+    }
+
+    if let Some(expected) = info.samples
+        && expected != decoded
+    {
+        bail!("FLAC stream has {} of {} samples", decoded, expected);
+    }
+    // an all-zero signature means the encoder didn't compute one
+    if info.md5sum != [0; 16] && md5.finalize()[..] != info.md5sum {
+        bail!("FLAC audio doesn't match its MD5 signature");
+    }
+
+    Ok(decoded as f64 / info.sample_rate as f64)
+}
+
+fn verify_mp4<R: Read + Seek>(reader: &mut R, file_len: u64) -> Result<f64> {
+    let mut position = 0u64;
+    let mut track: Option<TrackInfo> = None;
+    let mut media_data: Vec<(u64, u64)> = Vec::new();
+    let mut runs: Vec<SampleRun> = Vec::new();
+    let mut fragments = 0;
+    // None once a sample without a known duration shows up
+    let mut fragment_duration = Some(0u64);
+
+    while let Some(header) = read_box_header(reader)? {
+        let size = header.size.unwrap_or(file_len - position);
+        if position + size > file_len {
+            bail!(
+                "Truncated '{}' box: {} of {} bytes",
+                type_name(&header.box_type),
+                file_len - position,
+                size
+            );
+        }
+
+        match &header.box_type {
+            b"moov" => {
+                let payload = read_payload(reader, &header)?;
+                track = Some(parse_moov(&payload)?);
+            }
+            b"moof" => {
+                let Some(track) = &track else {
+                    bail!("Movie fragment before the movie header");
+                };
+                let payload = read_payload(reader, &header)?;
+                let (fragment_runs, duration) = parse_moof(&payload, position, track.defaults)?;
+                runs.extend(fragment_runs);
+                fragment_duration = fragment_duration.zip(duration).map(|(a, b)| a + b);
+                fragments += 1;
+            }
+            b"mdat" => {
+                let (start, end) = (position + header.header_len, position + size);
+                for run in runs.drain(..) {
+                    let run_end = run.offset + run.sizes.iter().map(|&s| s as u64).sum::<u64>();
+                    if run.offset < start || run_end > end {
+                        bail!("Fragment samples lie outside of their media data");
+                    }
+                }
+                media_data.push((start, end));
+                reader.seek(SeekFrom::Start(end))?;
+            }
+            _ => {
+                reader.seek(SeekFrom::Start(position + size))?;
+            }
+        }
+
+        position += size;
+        if header.size.is_none() {
+            break;
+        }
+    }
+
+    let Some(track) = track else {
+        bail!("No movie header in MP4");
+    };
+    if !runs.is_empty() {
+        bail!("Movie fragment without media data");
+    }
+    if track.timescale == 0 {
+        bail!("MP4 track without a timescale");
+    }
+
+    for &(offset, len) in &track.chunks {
+        if !media_data
+            .iter()
+            .any(|&(start, end)| offset >= start && offset + len <= end)
+        {
+            bail!("Sample table points outside of the media data");
+        }
+    }
+
+    let units = match fragment_duration {
+        Some(duration) if fragments > 0 => duration,
+        _ => track.duration,
+    };
+    if fragments == 0 && track.chunks.is_empty() {
+        bail!("No samples in MP4");
+    }
+
+    Ok(units as f64 / track.timescale as f64)
+}
+
+/// What the movie header says about the first track
+struct TrackInfo {
+    timescale: u32,
+    duration: u64,
+    defaults: TrackDefaults,
+    /// (offset, length) of every chunk in the sample tables, empty for fragmented files
+    chunks: Vec<(u64, u64)>,
+}
+
+fn parse_moov(moov: &[u8]) -> Result<TrackInfo> {
+    let mdhd = child_path(moov, &[b"trak", b"mdia", b"mdhd"])?.context("No media header in MP4")?;
+    let (timescale, duration) = match mdhd.first() {
+        Some(1) => (be_u32(mdhd, 20)?, be_u64(mdhd, 24)?),
+        _ => (be_u32(mdhd, 12)?, be_u32(mdhd, 16)? as u64),
+    };
+
+    let mut chunks = Vec::new();
+    for (box_type, trak) in children(moov)? {
+        if &box_type != b"trak" {
+            continue;
+        }
+        let (_, header_len, _) = child_header(trak)?;
+        if let Some(stbl) = child_path(&trak[header_len..], &[b"mdia", b"minf", b"stbl"])? {
+            chunks.extend(sample_chunks(stbl)?);
+        }
+    }
+
+    Ok(TrackInfo {
+        timescale,
+        duration,
+        defaults: track_defaults(moov)?,
+        chunks,
+    })
+}
+
+/// Resolves the sample tables of a track into the byte ranges of its chunks
+fn sample_chunks(stbl: &[u8]) -> Result<Vec<(u64, u64)>> {
+    let stsz = child(stbl, b"stsz")?.context("No sample sizes in MP4 track")?;
+    let fixed_size = be_u32(stsz, 4)?;
+    let sample_count = be_u32(stsz, 8)? as usize;
+    let sample_size = |i: usize| -> Result<u64> {
+        match fixed_size {
+            0 => Ok(be_u32(stsz, 12 + i * 4)? as u64),
+            size => Ok(size as u64),
+        }
+    };
+
+    if let Some(stts) = child(stbl, b"stts")? {
+        let mut timed = 0usize;
+        for i in 0..be_u32(stts, 4)? as usize {
+            timed += be_u32(stts, 8 + i * 8)? as usize;
+        }
+        if timed != sample_count {
+            bail!(
+                "Sample tables disagree on the sample count ({} timed, {} sized)",
+                timed,
+                sample_count
+            );
+        }
+    }
+
+    let offsets: Vec<u64> = if let Some(stco) = child(stbl, b"stco")? {
+        (0..be_u32(stco, 4)? as usize)
+            .map(|i| be_u32(stco, 8 + i * 4).map(u64::from))
+            .collect::<Result<_>>()?
+    } else if let Some(co64) = child(stbl, b"co64")? {
+        (0..be_u32(co64, 4)? as usize)
+            .map(|i| be_u64(co64, 8 + i * 8))
+            .collect::<Result<_>>()?
+    } else {
+        bail!("No chunk offsets in MP4 track");
+    };
+
+    // (first chunk, samples per chunk), chunks numbered from 1
+    let stsc = child(stbl, b"stsc")?.context("No sample-to-chunk table in MP4 track")?;
+    let runs: Vec<(u32, u32)> = (0..be_u32(stsc, 4)? as usize)
+        .map(|i| Ok((be_u32(stsc, 8 + i * 12)?, be_u32(stsc, 12 + i * 12)?)))
+        .collect::<Result<_>>()?;
+
+    let mut chunks = Vec::with_capacity(offsets.len());
+    let mut sample = 0usize;
+    for (index, &offset) in offsets.iter().enumerate() {
+        let number = index as u32 + 1;
+        let per_chunk = runs
+            .iter()
+            .rev()
+            .find(|(first, _)| *first <= number)
+            .map_or(0, |(_, count)| *count as usize);
+        if sample + per_chunk > sample_count {
+            bail!("Chunks hold more samples than the sample table");
+        }
+
+        let mut len = 0;
+        for i in sample..sample + per_chunk {
+            len += sample_size(i)?;
+        }
+        sample += per_chunk;
+        chunks.push((offset, len));
+    }
+
+    if sample != sample_count {
+        bail!(
+            "Chunks hold {} of {} samples in the sample table",
+            sample,
+            sample_count
+        );
+    }
+
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4box::{full_box, make_box};
+    use std::io::Cursor;
+
+    const BLOCK_SIZE: usize = 16;
+
+    fn crc8(data: &[u8]) -> u8 {
+        let mut crc = 0u8;
+        for &byte in data {
+            crc ^= byte;
+            for _ in 0..8 {
+                crc = if crc & 0x80 != 0 {
+                    crc << 1 ^ 0x07
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        crc
+    }
+
+    fn crc16(data: &[u8]) -> u16 {
+        let mut crc = 0u16;
+        for &byte in data {
+            crc ^= (byte as u16) << 8;
+            for _ in 0..8 {
+                crc = if crc & 0x8000 != 0 {
+                    crc << 1 ^ 0x8005
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        crc
+    }
+
+    /// A 44.1 kHz 16-bit stereo FLAC stream of verbatim frames
+    fn flac_stream(frames: &[[[i16; BLOCK_SIZE]; 2]], md5: [u8; 16]) -> Vec<u8> {
+        let total = (frames.len() * BLOCK_SIZE) as u64;
+        let mut info = Vec::new();
+        info.extend_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+        info.extend_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+        info.extend_from_slice(&[0; 6]);
+        info.extend_from_slice(&(44100u64 << 44 | 1 << 41 | 15 << 36 | total).to_be_bytes());
+        info.extend_from_slice(&md5);
+
+        let mut out = b"fLaC".to_vec();
+        out.extend_from_slice(&[0x80, 0, 0, info.len() as u8]);
+        out.extend_from_slice(&info);
+
+        for (number, channels) in frames.iter().enumerate() {
+            // fixed block size given in 8 bits, 44.1 kHz, two independent 16-bit channels
+            let mut frame = vec![0xff, 0xf8, 0x69, 0x18, number as u8, BLOCK_SIZE as u8 - 1];
+            frame.push(crc8(&frame));
+            for channel in channels {
+                frame.push(0x02); // verbatim subframe
+                for sample in channel {
+                    frame.extend_from_slice(&sample.to_be_bytes());
+                }
+            }
+            frame.extend_from_slice(&crc16(&frame).to_be_bytes());
+            out.extend_from_slice(&frame);
+        }
+        out
+    }
+
+    fn test_frames() -> Vec<[[i16; BLOCK_SIZE]; 2]> {
+        (0..3)
+            .map(|f| {
+                let left = std::array::from_fn(|i| (f * 1000 + i as i16 * 37) - 700);
+                let right = std::array::from_fn(|i| -(i as i16) * 91);
+                [left, right]
+            })
+            .collect()
+    }
+
+    fn signature(frames: &[[[i16; BLOCK_SIZE]; 2]]) -> [u8; 16] {
+        let mut md5 = Md5::new();
+        for [left, right] in frames {
+            for i in 0..BLOCK_SIZE {
+                md5.update(left[i].to_le_bytes());
+                md5.update(right[i].to_le_bytes());
+            }
+        }
+        md5.finalize().into()
+    }
+
+    #[test]
+    fn test_verify_flac() {
+        let frames = test_frames();
+        let stream = flac_stream(&frames, signature(&frames));
+        let secs = verify_flac(Cursor::new(&stream)).unwrap();
+        assert!((secs - 48.0 / 44100.0).abs() < 1e-9);
+
+        // a changed sample breaks the frame CRC
+        let mut corrupt = stream.clone();
+        let last = corrupt.len() - 5;
+        corrupt[last] ^= 0x01;
+        assert!(verify_flac(Cursor::new(&corrupt)).is_err());
+
+        // a missing frame no longer matches the sample count
+        let truncated = &stream[..stream.len() - (7 + 2 * (1 + 2 * BLOCK_SIZE) + 2)];
+        let err = verify_flac(Cursor::new(truncated)).unwrap_err();
+        assert!(err.to_string().contains("32 of 48 samples"));
+    }
+
+    #[test]
+    fn test_verify_flac_md5_mismatch() {
+        let frames = test_frames();
+        let stream = flac_stream(&frames, [0x55; 16]);
+        let err = verify_flac(Cursor::new(&stream)).unwrap_err();
+        assert!(err.to_string().contains("MD5"));
+
+        // streams without a signature are only decoded
+        let stream = flac_stream(&frames, [0; 16]);
+        assert!(verify_flac(Cursor::new(&stream)).is_ok());
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// A progressive MP4 with 4 samples of 10 bytes in two chunks, moov first
+    fn progressive_mp4(chunk_offset_shift: u32) -> Vec<u8> {
+        let build = |mdat_start: u32| {
+            let mdhd = full_box(b"mdhd", 0, &u32s(&[0, 0, 1000, 4000, 0]));
+            let stts = full_box(b"stts", 0, &u32s(&[1, 4, 1000]));
+            let stsz = full_box(b"stsz", 0, &u32s(&[10, 4]));
+            let stsc = full_box(b"stsc", 0, &u32s(&[1, 1, 2, 1]));
+            let stco = full_box(
+                b"stco",
+                0,
+                &u32s(&[2, mdat_start + chunk_offset_shift, mdat_start + 20]),
+            );
+            let stbl = make_box(b"stbl", &[stts, stsz, stsc, stco].concat());
+            let minf = make_box(b"minf", &stbl);
+            let mdia = make_box(b"mdia", &[mdhd, minf].concat());
+            let moov = make_box(b"moov", &make_box(b"trak", &mdia));
+            [make_box(b"ftyp", b"M4A \0\0\0\0"), moov].concat()
+        };
+
+        let head_len = build(0).len() as u32;
+        let mut out = build(head_len + 8);
+        out.extend_from_slice(&make_box(b"mdat", &[0xaa; 40]));
+        out
+    }
+
+    #[test]
+    fn test_verify_progressive_mp4() {
+        let file = progressive_mp4(0);
+        let secs = verify_mp4(&mut Cursor::new(&file), file.len() as u64).unwrap();
+        assert!((secs - 4.0).abs() < 1e-9);
+
+        // a chunk that points past the media data
+        let file = progressive_mp4(25);
+        assert!(verify_mp4(&mut Cursor::new(&file), file.len() as u64).is_err());
+
+        // a file cut off inside the media data
+        let file = progressive_mp4(0);
+        let cut = &file[..file.len() - 3];
+        let err = verify_mp4(&mut Cursor::new(cut), cut.len() as u64).unwrap_err();
+        assert!(err.to_string().contains("Truncated 'mdat' box"));
+    }
+}